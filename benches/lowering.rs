@@ -0,0 +1,55 @@
+// Copyright 2018 The RLS Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![feature(test)]
+
+extern crate rls_analysis;
+#[macro_use]
+extern crate derive_new;
+extern crate test;
+use test::Bencher;
+
+use std::path::{Path, PathBuf};
+
+use rls_analysis::{AnalysisHost, AnalysisLoader, SearchDirectory};
+
+#[derive(Clone, new)]
+struct TestAnalysisLoader {
+    path: PathBuf,
+}
+
+impl AnalysisLoader for TestAnalysisLoader {
+    fn needs_hard_reload(&self, _path_prefix: &Path) -> bool {
+        true
+    }
+
+    fn fresh_host(&self) -> AnalysisHost<Self> {
+        AnalysisHost::new_with_loader(self.clone())
+    }
+
+    fn set_path_prefix(&mut self, _path_prefix: &Path) {}
+
+    fn abs_path_prefix(&self) -> Option<PathBuf> {
+        panic!();
+    }
+
+    fn search_directories(&self) -> Vec<SearchDirectory> {
+        vec![SearchDirectory::new(self.path.clone(), None)]
+    }
+}
+
+// Reads and lowers the data for the standard libraries, which are lowered in
+// parallel.
+#[bench]
+fn lower_rust_analysis(b: &mut Bencher) {
+    let data_path = Path::new("test_data/rust-analysis");
+    b.iter(|| {
+        let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(data_path.to_owned()));
+        host.reload(data_path, data_path).unwrap();
+    })
+}
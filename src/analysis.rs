@@ -6,32 +6,28 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::cmp::{Ordering, Reverse};
 use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 use std::iter;
-use fst;
+use fst::{self, Streamer};
+use span::{Column, Position, Row, ZeroIndexed};
 
-use {Id, Span, SymbolQuery};
-use raw::{CrateId, DefKind};
+use {AError, AResult, DefFilter, Id, Span, SymbolResult, SymbolQuery};
+use outline::{self, OutlineNode};
+use symbol_query::{Cursor, Page};
+use raw::{CrateId, DefKind, ImportKind};
 
 /// This is the main database that contains all the collected symbol information,
 /// such as definitions, their mapping between spans, hierarchy and so on,
 /// organized in a per-crate fashion.
-#[derive(Debug)]
+#[derive(Debug, RustcEncodable, RustcDecodable)]
 crate struct Analysis {
     /// Contains lowered data with global inter-crate `Id`s per each crate.
     pub per_crate: HashMap<CrateId, PerCrateAnalysis>,
 
-    // This is a bit of a hack and should be considered temporary. A def has an
-    // entry if there exists an import of the def which aliases it. We use this
-    // for find_all_refs with unique spans to ensure that clients don't rename a
-    // definition when they only mean to rename an alias.
-    //
-    // In the future we should handle imports, in particular aliasing ones, more
-    // explicitly and then this can be removed.
-    crate aliased_imports: HashSet<Id>,
-
     // Maps a crate names to the crate ids for all crates with that name.
     crate crate_names: HashMap<String, Vec<CrateId>>,
 
@@ -44,8 +40,14 @@ pub struct PerCrateAnalysis {
     // Map span to id of def (either because it is the span of the def, or of
     // the def for the ref).
     pub def_id_for_span: HashMap<Span, Ref>,
+    // The keys of `def_id_for_span` for each file, sorted by start position
+    // (and for spans with the same start, outermost first, so the innermost
+    // span covering a position is the last candidate). Used to find the def or
+    // ref at a position.
+    pub spans_per_file: HashMap<PathBuf, Vec<Span>>,
     pub defs: HashMap<Id, Def>,
     pub defs_per_file: HashMap<PathBuf, Vec<Id>>,
+    pub defs_per_kind: Vec<(DefKind, Vec<Id>)>,
     pub children: HashMap<Id, HashSet<Id>>,
     pub def_names: HashMap<String, Vec<Id>>,
 
@@ -54,9 +56,26 @@ pub struct PerCrateAnalysis {
     pub def_fst: fst::Map,
     pub def_fst_values: Vec<Vec<Id>>,
 
-    pub ref_spans: HashMap<Id, Vec<Span>>,
+    // The spans of the refs to each def, with the kind of each ref.
+    pub ref_spans: HashMap<Id, Vec<(Span, RefKind)>>,
     pub globs: HashMap<Span, Glob>,
     pub impls: HashMap<Id, Vec<Span>>,
+    // The impl blocks in each file, used for outlines.
+    pub impls_per_file: HashMap<PathBuf, Vec<Impl>>,
+    // The imports in each file, sorted by position.
+    pub imports_per_file: HashMap<PathBuf, Vec<Import>>,
+    // Maps a def to the imports of it (copied from `imports_per_file`), sorted
+    // by file and position.
+    pub importers: HashMap<Id, Vec<Import>>,
+    // The type hierarchy. Each relation is recorded for both the def it is
+    // from and the def it is to.
+    pub relations: HashMap<Id, Vec<Relation>>,
+    // Calls between functions. Each call is recorded for both its caller and
+    // its callee.
+    pub calls: HashMap<Id, Vec<Call>>,
+    // A digest of the raw data for each file, for finding the files which
+    // have changed when the crate is reloaded.
+    pub file_digests: HashMap<PathBuf, u64>,
 
     pub root_id: Option<Id>,
     pub timestamp: SystemTime,
@@ -67,17 +86,16 @@ pub struct PerCrateAnalysis {
     pub global_crate_num: u32,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
 pub enum Ref {
     // The common case - a reference to a single definition.
     Id(Id),
     // Two defs contribute to a single reference, occurs in the field name
     // shorthand, maybe other places.
     Double(Id, Id),
-    // Multiple ids, we record only the first def we found, plus a count of defs.
-    // Should only happen due to generated code which has not been well-filtered
-    // by the compiler.
-    Multi(Id, usize),
+    // More than two defs, in the order they were found. Should only happen due
+    // to generated code which has not been well-filtered by the compiler.
+    Multi(Vec<Id>),
 }
 
 impl Ref {
@@ -85,20 +103,29 @@ impl Ref {
         match *self {
             Ref::Id(id) => id,
             Ref::Double(id, _) => id,
-            Ref::Multi(id, _) => id,
+            Ref::Multi(ref ids) => ids[0],
         }
     }
 
-    pub fn add_id(&self, def_id: Id) -> Ref {
+    /// All the defs referred to.
+    pub fn ids(&self) -> Vec<Id> {
         match *self {
-            Ref::Id(id) => Ref::Double(id, def_id),
-            Ref::Double(id, _) => Ref::Multi(id, 3),
-            Ref::Multi(id, n) => Ref::Multi(id, n + 1),
+            Ref::Id(id) => vec![id],
+            Ref::Double(id1, id2) => vec![id1, id2],
+            Ref::Multi(ref ids) => ids.clone(),
+        }
+    }
+
+    pub fn add_id(&mut self, def_id: Id) {
+        match *self {
+            Ref::Id(id) => *self = Ref::Double(id, def_id),
+            Ref::Double(id1, id2) => *self = Ref::Multi(vec![id1, id2, def_id]),
+            Ref::Multi(ref mut ids) => ids.push(def_id),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
 pub struct Def {
     pub kind: DefKind,
     pub span: Span,
@@ -108,31 +135,251 @@ pub struct Def {
     pub parent: Option<Id>,
     pub value: String,
     pub docs: String,
-    // pub sig: Option<Signature>,
+    pub sig: Option<Signature>,
+    pub attributes: Vec<Attribute>,
 }
 
-#[derive(Debug, Clone)]
+/// The signature of a def, e.g., `fn foo(x: Bar) -> Baz`. `defs` and `refs`
+/// give the byte ranges within `text` of any identifiers, and the ids of the
+/// defs they define or refer to, respectively.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
 pub struct Signature {
-    pub span: Span,
     pub text: String,
-    pub ident_start: u32,
-    pub ident_end: u32,
     pub defs: Vec<SigElement>,
     pub refs: Vec<SigElement>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
 pub struct SigElement {
     pub id: Id,
     pub start: usize,
     pub end: usize,
 }
 
-#[derive(Debug)]
+/// What a ref is a use of. Save-analysis data does not say whether a ref to a
+/// variable reads, writes or borrows it (rustc's borrow data is not read), so
+/// all such refs are `Variable`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, RustcEncodable, RustcDecodable)]
+pub enum RefKind {
+    /// A ref to a function or method, e.g., a call.
+    Function,
+    Mod,
+    Type,
+    /// A ref to a local, field, const or static.
+    Variable,
+    /// The imported name or alias in a `use` item.
+    Import,
+    /// A macro use.
+    Macro,
+    /// An item in a trait impl, which refers to the trait item it implements.
+    Impl,
+}
+
+/// An attribute of a def, e.g., `derive(Debug)` for `#[derive(Debug)]`.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Attribute {
+    pub value: String,
+    pub span: Span,
+}
+
+impl Attribute {
+    /// The name of the attribute, e.g., `derive` for `derive(Debug)`.
+    pub fn name(&self) -> &str {
+        let end = self.value
+            .find(|c: char| c == '(' || c == '=' || c.is_whitespace())
+            .unwrap_or(self.value.len());
+        &self.value[..end]
+    }
+}
+
+/// An edge in the type hierarchy. For `RelationKind::Impl`, the type `from`
+/// implements the trait `to`; for `RelationKind::SuperTrait`, the trait `from`
+/// is a supertrait of the trait `to`.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct Relation {
+    pub kind: RelationKind,
+    pub span: Span,
+    pub from: Id,
+    pub to: Id,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, RustcEncodable, RustcDecodable)]
+pub enum RelationKind {
+    Impl,
+    SuperTrait,
+}
+
+/// A ref at `span` to the function or method `callee`, from within the body of
+/// the function or method `caller`.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct Call {
+    pub caller: Id,
+    pub callee: Id,
+    pub span: Span,
+}
+
+/// An impl block. `span` is the span of the self type in the impl's header.
+/// `self_id` and `trait_id` are the ids of the self type and of the trait (for
+/// trait impls), if they are known; `children` are the ids of the impl's items.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct Impl {
+    pub span: Span,
+    pub parent: Option<Id>,
+    pub self_id: Option<Id>,
+    pub trait_id: Option<Id>,
+    pub children: Vec<Id>,
+}
+
+/// A `use` or `extern crate` item, or one of the names imported by a `use`
+/// with a list (e.g., `use foo::{bar, baz};` is two imports). `span` is the
+/// span of the imported name. For an import like `use foo::bar as baz;`,
+/// `alias` is the span of `baz` and `name` is `baz`. `target` is the id of the
+/// imported def, if it is known. Glob imports have no target; their `value`
+/// is the list of names they import.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Import {
+    pub kind: ImportKind,
+    pub span: Span,
+    pub alias: Option<Span>,
+    pub name: String,
+    pub value: String,
+    pub target: Option<Id>,
+    pub parent: Option<Id>,
+}
+
+/// How a crate's defs changed when it was updated, removed or added by a
+/// reload, see `AnalysisHost::changes`. All lists are sorted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeSet {
+    pub krate: CrateId,
+    pub kind: CrateChange,
+    /// The files in which any defs or refs were added, removed or changed.
+    pub files: Vec<PathBuf>,
+    pub added: Vec<Id>,
+    pub removed: Vec<Id>,
+    /// Defs which exist before and after, but whose name, span, docs, etc.
+    /// changed.
+    pub modified: Vec<Id>,
+}
+
+impl ChangeSet {
+    // The changes from `old` to `new`, either of which may be missing if the
+    // crate is being added or removed.
+    crate fn new(
+        krate: CrateId,
+        old: Option<&PerCrateAnalysis>,
+        new: Option<&PerCrateAnalysis>,
+    ) -> ChangeSet {
+        let empty = HashMap::new();
+        let old_defs = old.map_or(&empty, |c| &c.defs);
+        let new_defs = new.map_or(&empty, |c| &c.defs);
+
+        let only_in = |a: &HashMap<Id, Def>, b: &HashMap<Id, Def>| -> Vec<Id> {
+            a.keys().filter(|id| !b.contains_key(id)).cloned().collect()
+        };
+        let mut added = only_in(new_defs, old_defs);
+        let mut removed = only_in(old_defs, new_defs);
+        let mut modified: Vec<_> = new_defs
+            .iter()
+            .filter(|&(id, def)| old_defs.get(id).map_or(false, |old| old != def))
+            .map(|(id, _)| *id)
+            .collect();
+        added.sort();
+        removed.sort();
+        modified.sort();
+
+        let old_files = old.map_or(HashMap::new(), |c| c.entries_per_file());
+        let new_files = new.map_or(HashMap::new(), |c| c.entries_per_file());
+        let mut files: Vec<PathBuf> = old_files
+            .keys()
+            .chain(new_files.keys().filter(|f| !old_files.contains_key(*f)))
+            .filter(|f| old_files.get(*f) != new_files.get(*f))
+            .map(|f| f.to_path_buf())
+            .collect();
+        files.sort();
+
+        let kind = match (old, new) {
+            (None, _) => CrateChange::Added,
+            (_, None) => CrateChange::Removed,
+            _ => CrateChange::Updated,
+        };
+        ChangeSet { krate, kind, files, added, removed, modified }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kind == CrateChange::Updated
+            && self.files.is_empty()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+            && self.modified.is_empty()
+    }
+
+    // The events to send to subscribers for these changes.
+    crate fn events(&self) -> Vec<ChangeEvent> {
+        let krate = self.krate.clone();
+        let mut events = vec![
+            match self.kind {
+                CrateChange::Added => ChangeEvent::CrateAdded(krate.clone()),
+                CrateChange::Removed => ChangeEvent::CrateRemoved(krate.clone()),
+                CrateChange::Updated => ChangeEvent::CrateUpdated {
+                    krate: krate.clone(),
+                    files: self.files.clone(),
+                },
+            },
+        ];
+        if !(self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()) {
+            events.push(ChangeEvent::DefsChanged {
+                krate,
+                added: self.added.clone(),
+                removed: self.removed.clone(),
+                modified: self.modified.clone(),
+            });
+        }
+        events
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrateChange {
+    Added,
+    Removed,
+    Updated,
+}
+
+/// A change to the loaded data, sent to the callbacks registered with
+/// `AnalysisHost::subscribe`. For each crate which changes, the crate event
+/// is sent first, followed by `DefsChanged` if any of its defs changed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangeEvent {
+    CrateAdded(CrateId),
+    CrateRemoved(CrateId),
+    /// Some of the crate's defs or refs in `files` changed.
+    CrateUpdated { krate: CrateId, files: Vec<PathBuf> },
+    DefsChanged { krate: CrateId, added: Vec<Id>, removed: Vec<Id>, modified: Vec<Id> },
+}
+
+// The defs, spans and refs of a crate in one file, for finding which files
+// have changed.
+#[derive(PartialEq, Default)]
+struct FileEntries<'a> {
+    defs: HashMap<Id, &'a Def>,
+    spans: HashMap<&'a Span, &'a Ref>,
+    refs: HashSet<(Id, &'a Span, RefKind)>,
+}
+
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
 pub struct Glob {
     pub value: String,
 }
 
+impl Glob {
+    /// The names imported by the glob, `value` is a comma-separated list of
+    /// them.
+    pub fn names(&self) -> Vec<&str> {
+        self.value.split(',').map(|n| n.trim()).filter(|n| !n.is_empty()).collect()
+    }
+}
+
 
 impl PerCrateAnalysis {
     pub fn new(timestamp: SystemTime, path: Option<PathBuf>) -> PerCrateAnalysis {
@@ -140,8 +387,10 @@ impl PerCrateAnalysis {
             fst::Map::from_iter(iter::empty::<(String, u64)>()).unwrap();
         PerCrateAnalysis {
             def_id_for_span: HashMap::new(),
+            spans_per_file: HashMap::new(),
             defs: HashMap::new(),
             defs_per_file: HashMap::new(),
+            defs_per_kind: Vec::new(),
             children: HashMap::new(),
             def_names: HashMap::new(),
             def_fst: empty_fst,
@@ -149,6 +398,12 @@ impl PerCrateAnalysis {
             ref_spans: HashMap::new(),
             globs: HashMap::new(),
             impls: HashMap::new(),
+            impls_per_file: HashMap::new(),
+            imports_per_file: HashMap::new(),
+            importers: HashMap::new(),
+            relations: HashMap::new(),
+            calls: HashMap::new(),
+            file_digests: HashMap::new(),
             root_id: None,
             timestamp,
             path,
@@ -165,13 +420,109 @@ impl PerCrateAnalysis {
             None => false,
         }
     }
+
+    // Copies the entries for `files` in the per-file indexes from `old`, the
+    // data for this crate when the files were last lowered.
+    crate fn reuse_files(&mut self, old: &PerCrateAnalysis, files: &HashSet<PathBuf>) {
+        if files.is_empty() {
+            return;
+        }
+        for file in files {
+            if let Some(ids) = old.defs_per_file.get(file) {
+                self.defs_per_file.insert(file.clone(), ids.clone());
+            }
+            if let Some(imports) = old.imports_per_file.get(file) {
+                self.imports_per_file.insert(file.clone(), imports.clone());
+            }
+            for span in old.spans_per_file.get(file).into_iter().flat_map(|spans| spans) {
+                self.def_id_for_span.insert(span.clone(), old.def_id_for_span[span].clone());
+            }
+        }
+
+        let reused = |span: &Span| files.contains(&span.file);
+        for (id, refs) in &old.ref_spans {
+            let refs: Vec<_> = refs
+                .iter()
+                .filter(|&&(ref span, _)| reused(span))
+                .cloned()
+                .collect();
+            if !refs.is_empty() {
+                self.ref_spans.insert(*id, refs);
+            }
+        }
+        for (id, calls) in &old.calls {
+            let calls: Vec<_> = calls.iter().filter(|call| reused(&call.span)).cloned().collect();
+            if !calls.is_empty() {
+                self.calls.insert(*id, calls);
+            }
+        }
+        for (id, imports) in &old.importers {
+            let imports: Vec<_> = imports.iter().filter(|i| reused(&i.span)).cloned().collect();
+            if !imports.is_empty() {
+                self.importers.insert(*id, imports);
+            }
+        }
+        self.globs.extend(
+            old.globs
+                .iter()
+                .filter(|&(span, _)| reused(span))
+                .map(|(span, glob)| (span.clone(), glob.clone())),
+        );
+    }
+
+    // Rebuilds `spans_per_file` from `def_id_for_span`.
+    crate fn index_spans(&mut self) {
+        self.spans_per_file.clear();
+        for span in self.def_id_for_span.keys() {
+            self.spans_per_file
+                .entry(span.file.clone())
+                .or_insert_with(Vec::new)
+                .push(span.clone());
+        }
+        for spans in self.spans_per_file.values_mut() {
+            spans.sort_by_key(|s| (s.range.start(), Reverse(s.range.end())));
+        }
+    }
+
+    fn entries_per_file<'a>(&'a self) -> HashMap<&'a Path, FileEntries<'a>> {
+        let mut result: HashMap<&Path, FileEntries> = HashMap::new();
+        for (id, def) in &self.defs {
+            result.entry(&def.span.file).or_insert_with(Default::default).defs.insert(*id, def);
+        }
+        for (span, r) in &self.def_id_for_span {
+            result.entry(&span.file).or_insert_with(Default::default).spans.insert(span, r);
+        }
+        for (id, refs) in &self.ref_spans {
+            for &(ref span, kind) in refs {
+                result
+                    .entry(&span.file)
+                    .or_insert_with(Default::default)
+                    .refs
+                    .insert((*id, span, kind));
+            }
+        }
+        result
+    }
+
+    crate fn span_at_position(&self, file: &Path, pos: Position<ZeroIndexed>) -> Option<&Span> {
+        let spans = self.spans_per_file.get(file)?;
+        // Only spans which start at or before `pos` can cover it, and of those
+        // the one which starts last is the innermost.
+        let candidates = spans
+            .binary_search_by(|s| if s.range.start() <= pos {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            })
+            .unwrap_err();
+        spans[..candidates].iter().rev().find(|s| pos < s.range.end())
+    }
 }
 
 impl Analysis {
     pub fn new() -> Analysis {
         Analysis {
             per_crate: HashMap::new(),
-            aliased_imports: HashSet::new(),
             crate_names: HashMap::new(),
             // TODO don't hardcode these
             doc_url_base: "https://doc.rust-lang.org/nightly".to_owned(),
@@ -187,14 +538,118 @@ impl Analysis {
             .collect()
     }
 
-    pub fn update(&mut self, crate_id: CrateId, per_crate: PerCrateAnalysis) {
-        self.per_crate.insert(crate_id, per_crate);
+    // Adds or replaces a crate, returning the crate it replaces, if any.
+    pub fn update(
+        &mut self,
+        crate_id: CrateId,
+        per_crate: PerCrateAnalysis,
+    ) -> Option<PerCrateAnalysis> {
+        let crates_with_name =
+            self.crate_names.entry(crate_id.name.clone()).or_insert_with(Vec::new);
+        if !crates_with_name.contains(&crate_id) {
+            crates_with_name.push(crate_id.clone());
+        }
+        self.per_crate.insert(crate_id, per_crate)
+    }
+
+    // The changes from `old` (if there is any loaded data) to `new`.
+    pub fn changes(old: Option<&Analysis>, new: &Analysis) -> Vec<ChangeSet> {
+        let mut changes: Vec<_> = new.per_crate
+            .iter()
+            .map(|(id, c)| {
+                let old = old.and_then(|a| a.per_crate.get(id));
+                ChangeSet::new(id.clone(), old, Some(c))
+            })
+            .collect();
+        if let Some(old) = old {
+            changes.extend(
+                old.per_crate
+                    .iter()
+                    .filter(|&(id, _)| !new.per_crate.contains_key(id))
+                    .map(|(id, c)| ChangeSet::new(id.clone(), Some(c), None))
+            );
+        }
+        changes.retain(|c| !c.is_empty());
+        changes
+    }
+
+    pub fn remove_crate(&mut self, crate_id: &CrateId) -> Option<PerCrateAnalysis> {
+        let remove_name = match self.crate_names.get_mut(&crate_id.name) {
+            Some(ids) => {
+                ids.retain(|id| id != crate_id);
+                ids.is_empty()
+            }
+            None => false,
+        };
+        if remove_name {
+            self.crate_names.remove(&crate_id.name);
+        }
+
+        self.per_crate.remove(crate_id)
+    }
+
+    // Removes crates whose save-analysis file (see `timestamps`) no longer
+    // exists or, if `modified`, has been modified since it was read. Crates
+    // which were not read from a file are kept.
+    pub fn remove_stale_crates(&mut self, modified: bool) -> Vec<(CrateId, PerCrateAnalysis)> {
+        let stale: Vec<_> = self.per_crate
+            .iter()
+            .filter(|&(_, c)| {
+                c.path.as_ref().map_or(false, |path| match fs::metadata(path) {
+                    Ok(m) => modified && m.modified().map_or(true, |t| t != c.timestamp),
+                    Err(_) => true,
+                })
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+        stale
+            .into_iter()
+            .filter_map(|id| {
+                info!("removing stale crate {:?}", id);
+                self.remove_crate(&id).map(|c| (id, c))
+            })
+            .collect()
     }
 
     pub fn has_def(&self, id: Id) -> bool {
         self.per_crate.values().any(|c| c.defs.contains_key(&id))
     }
 
+    // Applies `f` to each call to or from `id`, returning the ids it selects
+    // together with the spans of the calls, grouped by id.
+    pub fn related_calls<F>(&self, id: Id, f: F) -> Vec<(Id, Vec<Span>)>
+    where
+        F: Fn(&Call) -> Option<Id>,
+    {
+        let mut result: Vec<(Id, Vec<Span>)> = vec![];
+        let calls = self.for_all_crates(|c| {
+            c.calls.get(&id).map(|calls| {
+                calls.iter().filter_map(|call| f(call).map(|id| (id, call.span.clone()))).collect()
+            })
+        });
+        for (id, span) in calls {
+            match result.iter().position(|&(other, _)| other == id) {
+                Some(i) => result[i].1.push(span),
+                None => result.push((id, vec![span])),
+            }
+        }
+        result
+    }
+
+    // Applies `f` to each relation to or from `id`, returning the ids it
+    // selects, without duplicates.
+    pub fn related_ids<F>(&self, id: Id, f: F) -> Vec<Id>
+    where
+        F: Fn(&Relation) -> Option<Id>,
+    {
+        let mut seen = HashSet::new();
+        self.for_all_crates(|c| {
+            c.relations.get(&id).map(|relations| {
+                relations.iter().filter_map(|r| f(r)).collect()
+            })
+        }).into_iter().filter(|id| seen.insert(*id)).collect()
+    }
+
     pub fn for_each_crate<F, T>(&self, f: F) -> Option<T>
     where
         F: Fn(&PerCrateAnalysis) -> Option<T>,
@@ -232,17 +687,28 @@ impl Analysis {
         result
     }
 
-    pub fn def_id_for_span(&self, span: &Span) -> Option<Id> {
+    pub fn def_id_for_span(&self, span: &Span) -> AResult<Id> {
         self.ref_for_span(span).map(|r| r.some_id())
     }
 
-    pub fn ref_for_span(&self, span: &Span) -> Option<Ref> {
+    // The ids of all the defs at `span`, without duplicates.
+    pub fn def_ids_for_span(&self, span: &Span) -> AResult<Vec<Id>> {
+        self.ref_for_span(span).map(|r| {
+            let mut ids = r.ids();
+            let mut seen = HashSet::new();
+            ids.retain(|id| seen.insert(*id));
+            ids
+        })
+    }
+
+    pub fn ref_for_span(&self, span: &Span) -> AResult<Ref> {
         self.for_each_crate(|c| c.def_id_for_span.get(span).map(|r| r.clone()))
+            .ok_or_else(|| AError::NoDefAtSpan(span.clone()))
     }
 
     // Like def_id_for_span, but will only return a def_id if it is in the same
     // crate.
-    pub fn local_def_id_for_span(&self, span: &Span) -> Option<Id> {
+    pub fn local_def_id_for_span(&self, span: &Span) -> AResult<Id> {
         self.for_each_crate(|c| {
             c.def_id_for_span
                 .get(span)
@@ -252,28 +718,139 @@ impl Analysis {
                 } else {
                     None
                 })
-        })
+        }).ok_or_else(|| AError::NoDefAtSpan(span.clone()))
+    }
+
+    // Like ref_for_span, but finds the innermost span which covers the given
+    // position, rather than requiring an exact span.
+    pub fn ref_at_position(
+        &self,
+        file: &Path,
+        row: Row<ZeroIndexed>,
+        col: Column<ZeroIndexed>,
+    ) -> AResult<(Span, Ref)> {
+        let pos = Position::new(row, col);
+        self.per_crate
+            .values()
+            .filter_map(|c| c.span_at_position(file, pos).map(|span| (span, c)))
+            .max_by_key(|&(span, _)| (span.range.start(), Reverse(span.range.end())))
+            .map(|(span, c)| (span.clone(), c.def_id_for_span[span].clone()))
+            .ok_or_else(|| AError::NoDefAtSpan(Span::new(row, row, col, col, file)))
     }
 
-    pub fn with_defs<F, T>(&self, id: Id, f: F) -> Option<T>
+    pub fn with_defs<F, T>(&self, id: Id, f: F) -> AResult<T>
     where
         F: Fn(&Def) -> T,
     {
         self.for_each_crate(|c| c.defs.get(&id).map(&f))
+            .ok_or(AError::UnknownId(id))
+    }
+
+    // Applies `f` to each of the known defs in `ids`, returning the distinct
+    // results in order. It is an error if none of the defs is known.
+    pub fn map_defs<F, T>(&self, ids: &[Id], f: F) -> AResult<Vec<T>>
+    where
+        F: Fn(&Def) -> T,
+        T: PartialEq,
+    {
+        let mut result = vec![];
+        for id in ids {
+            if let Ok(t) = self.with_defs(*id, &f) {
+                if !result.contains(&t) {
+                    result.push(t);
+                }
+            }
+        }
+        match ids.first() {
+            Some(&id) if result.is_empty() => Err(AError::UnknownId(id)),
+            _ => Ok(result),
+        }
     }
 
-    pub fn with_defs_and_then<F, T>(&self, id: Id, f: F) -> Option<T>
+    pub fn with_defs_and_then<F, T>(&self, id: Id, f: F) -> AResult<T>
     where
-        F: Fn(&Def) -> Option<T>,
+        F: Fn(&Def) -> AResult<T>,
     {
-        self.for_each_crate(|c| c.defs.get(&id).and_then(&f))
+        self.with_defs(id, f).and_then(|r| r)
     }
 
-    pub fn with_globs<F, T>(&self, span: &Span, f: F) -> Option<T>
+    pub fn with_globs<F, T>(&self, span: &Span, f: F) -> AResult<T>
     where
         F: Fn(&Glob) -> T,
     {
         self.for_each_crate(|c| c.globs.get(span).map(&f))
+            .ok_or_else(|| AError::NoDefAtSpan(span.clone()))
+    }
+
+    pub fn glob_expansion(&self, span: &Span) -> AResult<Vec<(String, Id)>> {
+        let names: Vec<String> =
+            self.with_globs(span, |g| g.names().into_iter().map(|n| n.to_owned()).collect())?;
+        let unknown = || AError::UnknownGlobModule(span.clone());
+        let module = self.glob_module(span).ok_or_else(unknown)?;
+        let module_file = self.with_defs(module, |def| def.span.file.clone())?;
+
+        let children = self.for_each_child(module, |id, def| (def.name.clone(), id))
+            .unwrap_or_else(Vec::new);
+        // Items which the module re-exports. The module's imports are in the
+        // same file as its def.
+        let reexports = self.for_all_crates(|c| {
+            Some(
+                c.imports_per_file
+                    .get(&module_file)?
+                    .iter()
+                    .filter(|i| i.parent == Some(module))
+                    .filter_map(|i| i.target.map(|t| (i.name.clone(), t)))
+                    .collect(),
+            )
+        });
+
+        let mut result: Vec<_> = names
+            .iter()
+            .filter_map(|name| {
+                let id = children
+                    .iter()
+                    .chain(reexports.iter())
+                    .find(|&&(ref n, _)| n == name)
+                    .map(|&(_, id)| id)?;
+                Some((name.clone(), id))
+            })
+            .collect();
+        // If none of the names are in the module, we found the wrong one.
+        if result.is_empty() && !names.is_empty() {
+            return Err(unknown());
+        }
+        result.sort();
+        Ok(result)
+    }
+
+    // The module imported from by the glob import at `span`, i.e., the def of
+    // the last segment of the glob's path (`bar` in `use foo::bar::*;`). That
+    // is the nearest ref to a module before the `*`, skipping any names
+    // imported by the same use tree (`baz` in `use foo::bar::{baz, *};`). We
+    // stop at any other def or ref, since the segment refs of some paths are
+    // not recorded.
+    fn glob_module(&self, span: &Span) -> Option<Id> {
+        let start = span.range.start();
+        self.for_each_crate(|c| {
+            let spans = c.spans_per_file.get(&span.file)?;
+            let before = spans.iter().take_while(|s| s.range.start() < start).count();
+            for s in spans[..before].iter().rev() {
+                if s.range.end() > start {
+                    // A span which covers the glob, e.g., the root module's.
+                    continue;
+                }
+                let ids = c.def_id_for_span[s].ids();
+                let kinds: Vec<_> = ids.iter().map(|&id| self.ref_kind(id, s)).collect();
+                if kinds.iter().all(|&k| k == Some(RefKind::Import)) {
+                    continue;
+                }
+                return ids.into_iter().zip(kinds).find(|&(id, kind)| {
+                    kind == Some(RefKind::Mod)
+                        && self.with_defs(id, |def| def.kind == DefKind::Mod).unwrap_or(false)
+                }).map(|(id, _)| id);
+            }
+            None
+        })
     }
 
     pub fn for_each_child<F, T>(&self, id: Id, mut f: F) -> Option<Vec<T>>
@@ -300,35 +877,168 @@ impl Analysis {
         Some(vec![])
     }
 
-    pub fn with_ref_spans<F, T>(&self, id: Id, f: F) -> Option<T>
-    where
-        F: Fn(&Vec<Span>) -> Option<T>,
-    {
-        self.for_each_crate(|c| c.ref_spans.get(&id).and_then(&f))
+    // The kind of the ref at `span` to `id`, if it is known.
+    pub fn ref_kind(&self, id: Id, span: &Span) -> Option<RefKind> {
+        self.with_ref_spans(id, |refs| {
+            refs.iter().find(|&&(ref s, _)| s == span).map(|&(_, kind)| kind)
+        })
     }
 
-    pub fn with_defs_per_file<F, T>(&self, file: &Path, f: F) -> Option<T>
+    pub fn with_ref_spans<F, T>(&self, id: Id, f: F) -> Option<T>
     where
-        F: Fn(&Vec<Id>) -> T,
+        F: Fn(&Vec<(Span, RefKind)>) -> Option<T>,
     {
-        self.for_each_crate(|c| c.defs_per_file.get(file).map(&f))
+        self.for_each_crate(|c| c.ref_spans.get(&id).and_then(&f))
     }
 
-    pub fn query_defs(&self, query: SymbolQuery) -> Vec<Def> {
+    pub fn query_defs(&self, query: SymbolQuery) -> Vec<SymbolResult> {
         let mut crates = Vec::with_capacity(self.per_crate.len());
         let stream = query.build_stream(
-            self.per_crate.values().map(|c| {
-                crates.push(c);
+            self.per_crate.iter().map(|(krate, c)| {
+                crates.push((krate, c));
                 &c.def_fst
             })
         );
 
-        query.search_stream(stream, |acc, e| {
-            let c = &crates[e.index];
-            let ids = &c.def_fst_values[e.value as usize];
-            acc.extend(
-                ids.iter().flat_map(|id| c.defs.get(id)).cloned()
-            );
+        let mut defs = query.search_stream(stream, |acc, e| {
+            let (krate, c) = crates[e.index];
+            query_matches(&query, krate, c, e.value, acc);
+        });
+        query.rank(&mut defs, |m| m.rank);
+        defs
+    }
+
+    // Returns the page of matching defs which follow `cursor`, and the cursor
+    // for the next page, if there is one.
+    pub fn query_defs_page(
+        &self,
+        query: SymbolQuery,
+        cursor: Option<Cursor>,
+    ) -> (Vec<SymbolResult>, Option<Cursor>) {
+        let mut crates = Vec::with_capacity(self.per_crate.len());
+        let mut stream = query.build_stream_from(
+            self.per_crate.iter().map(|(krate, c)| {
+                crates.push((krate, c));
+                &c.def_fst
+            }),
+            cursor.as_ref(),
+        );
+
+        let mut page = Page::new(&query, cursor);
+        let mut ranked = vec![];
+        while let Some((key, entries)) = stream.next() {
+            let mut group = vec![];
+            for e in entries {
+                let (krate, c) = crates[e.index];
+                query_matches(&query, krate, c, e.value, &mut group);
+            }
+            group.sort_by_key(|m| m.id);
+
+            // Fuzzy results are ranked, so we must see them all before paging.
+            let key = String::from_utf8_lossy(key);
+            if query.is_fuzzy() {
+                ranked.extend(group.into_iter().map(|m| (Cursor::new(m.rank, &key, m.id), m)));
+                continue;
+            }
+            if !group.into_iter().all(|m| page.push(Cursor::new(0, &key, m.id), m)) {
+                break;
+            }
+        }
+
+        ranked.sort_by(|a, b| a.0.cmp(&b.0));
+        for (position, m) in ranked {
+            if !page.push(position, m) {
+                break;
+            }
+        }
+        page.finish()
+    }
+
+    pub fn filter_defs(&self, filter: &DefFilter) -> Vec<SymbolResult> {
+        let crates: Vec<(&CrateId, &PerCrateAnalysis)> = match filter.crate_names() {
+            Some(names) => names
+                .iter()
+                .filter_map(|name| self.crate_names.get(name))
+                .flat_map(|ids| ids.iter())
+                .filter_map(|id| self.per_crate.get(id).map(|c| (id, c)))
+                .collect(),
+            None => self.per_crate.iter().collect(),
+        };
+
+        let mut result = vec![];
+        for (krate, c) in crates {
+            for id in filter.candidates(c) {
+                if let Some(def) = c.defs.get(&id) {
+                    if filter.matches(def) {
+                        result.push(SymbolResult::new(id, def, krate));
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    // The defs in `file`, in the order they were recorded.
+    pub fn symbols(&self, file: &Path) -> AResult<Vec<SymbolResult>> {
+        self.per_crate
+            .iter()
+            .filter_map(|(krate, c)| {
+                c.defs_per_file.get(file).map(|ids| {
+                    ids.iter()
+                        .filter_map(|id| c.defs.get(id).map(|def| (*id, def)))
+                        .map(|(id, def)| SymbolResult::new(id, def, krate))
+                        .collect()
+                })
+            })
+            .next()
+            .ok_or_else(|| AError::NoDefsInFile(file.to_owned()))
+    }
+
+    pub fn outline(&self, file: &Path) -> AResult<Vec<OutlineNode>> {
+        self.per_crate
+            .values()
+            .find(|c| c.defs_per_file.contains_key(file) || c.impls_per_file.contains_key(file))
+            .map(|c| {
+                let defs = c.defs_per_file
+                    .get(file)
+                    .map_or(vec![], |ids| {
+                        ids.iter()
+                            .filter_map(|id| c.defs.get(id).map(|def| (*id, def.clone())))
+                            .collect()
+                    });
+                let impls = c.impls_per_file.get(file).cloned().unwrap_or_default();
+                outline::build(defs, impls)
+            })
+            .ok_or_else(|| AError::NoDefsInFile(file.to_owned()))
+    }
+
+    pub fn imports_in_file(&self, file: &Path) -> AResult<Vec<Import>> {
+        self.per_crate
+            .values()
+            .find(|c| c.defs_per_file.contains_key(file) || c.imports_per_file.contains_key(file))
+            .map(|c| c.imports_per_file.get(file).cloned().unwrap_or_default())
+            .ok_or_else(|| AError::NoDefsInFile(file.to_owned()))
+    }
+
+    // Crates with the same name may share source files, so the same import
+    // may be found in more than one crate.
+    pub fn importers_of(&self, id: Id) -> Vec<Import> {
+        let mut result = self.for_all_crates(|c| c.importers.get(&id).cloned());
+        if self.per_crate.len() == 1 {
+            return result;
+        }
+        result.sort_by(|a: &Import, b| {
+            (&a.span.file, a.span.range.start()).cmp(&(&b.span.file, b.span.range.start()))
+        });
+        result.dedup();
+        result
+    }
+
+    // True if `id` is imported under an alias anywhere, in which case renaming
+    // it would also rename the alias.
+    pub fn is_imported_with_alias(&self, id: Id) -> bool {
+        self.per_crate.values().any(|c| {
+            c.importers.get(&id).map_or(false, |imports| imports.iter().any(|i| i.alias.is_some()))
         })
     }
 
@@ -339,3 +1049,43 @@ impl Analysis {
         self.for_all_crates(|c| c.def_names.get(name).map(&f))
     }
 }
+
+// Pushes the defs for the value of an entry in a `SymbolQuery` stream over
+// `c.def_fst` which satisfy the query's path qualifier.
+fn query_matches(
+    query: &SymbolQuery,
+    krate: &CrateId,
+    c: &PerCrateAnalysis,
+    value: u64,
+    acc: &mut Vec<SymbolResult>,
+) {
+    acc.extend(
+        c.def_fst_values[value as usize]
+            .iter()
+            .flat_map(|id| c.defs.get(id).map(|def| (*id, def)))
+            .filter(|&(_, def)| query.matches_qualname(&def.qualname))
+            .map(|(id, def)| SymbolResult {
+                ranges: query.match_ranges(&def.name),
+                rank: query.score(&def.name).unwrap_or(0),
+                ..SymbolResult::new(id, def, krate)
+            })
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ref_ids() {
+        let mut r = Ref::Id(Id(1));
+        assert_eq!(r.ids(), [Id(1)]);
+        r.add_id(Id(2));
+        assert_eq!(r, Ref::Double(Id(1), Id(2)));
+        r.add_id(Id(3));
+        r.add_id(Id(4));
+        assert_eq!(r, Ref::Multi(vec![Id(1), Id(2), Id(3), Id(4)]));
+        assert_eq!(r.some_id(), Id(1));
+        assert_eq!(r.ids(), [Id(1), Id(2), Id(3), Id(4)]);
+    }
+}
@@ -0,0 +1,582 @@
+// Copyright 2018 The RLS Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Reading and writing binary snapshots of lowered analysis data, so that a
+//! host can be restored without re-reading and re-lowering every
+//! save-analysis file.
+//!
+//! A snapshot is the `MAGIC` bytes and `VERSION`, followed by the master crate
+//! map and the `Analysis`, encoded with `rustc_serialize` into a simple
+//! little-endian format by `BinaryEncoder`.
+
+use analysis::{Analysis, PerCrateAnalysis};
+use raw::CrateId;
+use {AError, AResult};
+
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use fst;
+
+const MAGIC: &[u8; 4] = b"RLSA";
+// Must be bumped whenever the lowered data (or how it is encoded) changes.
+const VERSION: u32 = 2;
+
+type CacheResult<T> = Result<T, String>;
+
+pub fn write(
+    path: &Path,
+    analysis: &Analysis,
+    master_crate_map: &HashMap<CrateId, u32>,
+) -> AResult<()> {
+    let t_start = Instant::now();
+
+    let mut buf = MAGIC.to_vec();
+    {
+        let mut encoder = BinaryEncoder { buf: &mut buf };
+        VERSION.encode(&mut encoder)
+            .and_then(|_| master_crate_map.encode(&mut encoder))
+            .and_then(|_| analysis.encode(&mut encoder))
+            .map_err(|err| {
+                warn!("error encoding analysis cache: {}", err);
+                AError::InvalidCache
+            })?;
+    }
+    // Write to a temporary file and rename it into place, so that readers
+    // never see (and a crash never leaves) a partly written cache.
+    let mut tmp_name = path.file_name().ok_or(AError::Io(io::ErrorKind::InvalidInput))?.to_owned();
+    tmp_name.push(format!(".{}.tmp", process::id()));
+    let tmp_path = path.with_file_name(tmp_name);
+    let written = File::create(&tmp_path)
+        .and_then(|mut file| file.write_all(&buf).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&tmp_path, path));
+    if let Err(err) = written {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err.into());
+    }
+
+    let time = t_start.elapsed();
+    info!(
+        "wrote analysis cache to {:?} ({} bytes) in {:.2}s",
+        path,
+        buf.len(),
+        time.as_secs() as f64 + time.subsec_nanos() as f64 / 1_000_000_000.0
+    );
+    Ok(())
+}
+
+pub fn read(path: &Path) -> AResult<(Analysis, HashMap<CrateId, u32>)> {
+    let t_start = Instant::now();
+
+    let mut buf = vec![];
+    File::open(path)?.read_to_end(&mut buf)?;
+    if !buf.starts_with(MAGIC) {
+        warn!("{:?} is not an analysis cache", path);
+        return Err(AError::InvalidCache);
+    }
+
+    let mut decoder = BinaryDecoder { buf: &buf[MAGIC.len()..] };
+    let version = u32::decode(&mut decoder).map_err(|_| AError::InvalidCache)?;
+    if version != VERSION {
+        info!("analysis cache version mismatch; expected {} but got {}", VERSION, version);
+        return Err(AError::InvalidCache);
+    }
+    let result = Decodable::decode(&mut decoder)
+        .and_then(|master_crate_map| {
+            Analysis::decode(&mut decoder).map(|analysis| (analysis, master_crate_map))
+        })
+        .map_err(|err| {
+            warn!("error decoding analysis cache: {}", err);
+            AError::InvalidCache
+        })?;
+    if !decoder.buf.is_empty() {
+        warn!("trailing data in analysis cache");
+        return Err(AError::InvalidCache);
+    }
+
+    let time = t_start.elapsed();
+    info!(
+        "read analysis cache from {:?} in {:.2}s",
+        path,
+        time.as_secs() as f64 + time.subsec_nanos() as f64 / 1_000_000_000.0
+    );
+    Ok(result)
+}
+
+// `PerCrateAnalysis` holds an fst and a timestamp, neither of which implement
+// the serialization traits, so we encode it by hand. `spans_per_file` is not
+// encoded, it is rebuilt from `def_id_for_span`.
+impl Encodable for PerCrateAnalysis {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let timestamp = self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        s.emit_struct("PerCrateAnalysis", 21, |s| {
+            s.emit_struct_field("def_id_for_span", 0, |s| self.def_id_for_span.encode(s))?;
+            s.emit_struct_field("defs", 1, |s| self.defs.encode(s))?;
+            s.emit_struct_field("defs_per_file", 2, |s| self.defs_per_file.encode(s))?;
+            s.emit_struct_field("defs_per_kind", 3, |s| self.defs_per_kind.encode(s))?;
+            s.emit_struct_field("children", 4, |s| self.children.encode(s))?;
+            s.emit_struct_field("def_names", 5, |s| self.def_names.encode(s))?;
+            s.emit_struct_field("def_fst", 6, |s| self.def_fst.as_fst().as_bytes().encode(s))?;
+            s.emit_struct_field("def_fst_values", 7, |s| self.def_fst_values.encode(s))?;
+            s.emit_struct_field("ref_spans", 8, |s| self.ref_spans.encode(s))?;
+            s.emit_struct_field("globs", 9, |s| self.globs.encode(s))?;
+            s.emit_struct_field("impls", 10, |s| self.impls.encode(s))?;
+            s.emit_struct_field("impls_per_file", 11, |s| self.impls_per_file.encode(s))?;
+            s.emit_struct_field("imports_per_file", 12, |s| self.imports_per_file.encode(s))?;
+            s.emit_struct_field("importers", 13, |s| self.importers.encode(s))?;
+            s.emit_struct_field("relations", 14, |s| self.relations.encode(s))?;
+            s.emit_struct_field("calls", 15, |s| self.calls.encode(s))?;
+            s.emit_struct_field("file_digests", 16, |s| self.file_digests.encode(s))?;
+            s.emit_struct_field("root_id", 17, |s| self.root_id.encode(s))?;
+            s.emit_struct_field("timestamp", 18, |s| {
+                (timestamp.as_secs(), timestamp.subsec_nanos()).encode(s)
+            })?;
+            s.emit_struct_field("path", 19, |s| self.path.encode(s))?;
+            s.emit_struct_field("global_crate_num", 20, |s| self.global_crate_num.encode(s))
+        })
+    }
+}
+
+impl Decodable for PerCrateAnalysis {
+    fn decode<D: Decoder>(d: &mut D) -> Result<PerCrateAnalysis, D::Error> {
+        d.read_struct("PerCrateAnalysis", 21, |d| {
+            let mut per_crate = PerCrateAnalysis {
+                def_id_for_span: d.read_struct_field("def_id_for_span", 0, Decodable::decode)?,
+                spans_per_file: HashMap::new(),
+                defs: d.read_struct_field("defs", 1, Decodable::decode)?,
+                defs_per_file: d.read_struct_field("defs_per_file", 2, Decodable::decode)?,
+                defs_per_kind: d.read_struct_field("defs_per_kind", 3, Decodable::decode)?,
+                children: d.read_struct_field("children", 4, Decodable::decode)?,
+                def_names: d.read_struct_field("def_names", 5, Decodable::decode)?,
+                def_fst: d.read_struct_field("def_fst", 6, |d| {
+                    let bytes = Vec::<u8>::decode(d)?;
+                    fst::Map::from_bytes(bytes).map_err(|err| d.error(&err.to_string()))
+                })?,
+                def_fst_values: d.read_struct_field("def_fst_values", 7, Decodable::decode)?,
+                ref_spans: d.read_struct_field("ref_spans", 8, Decodable::decode)?,
+                globs: d.read_struct_field("globs", 9, Decodable::decode)?,
+                impls: d.read_struct_field("impls", 10, Decodable::decode)?,
+                impls_per_file: d.read_struct_field("impls_per_file", 11, Decodable::decode)?,
+                imports_per_file: d.read_struct_field("imports_per_file", 12, Decodable::decode)?,
+                importers: d.read_struct_field("importers", 13, Decodable::decode)?,
+                relations: d.read_struct_field("relations", 14, Decodable::decode)?,
+                calls: d.read_struct_field("calls", 15, Decodable::decode)?,
+                file_digests: d.read_struct_field("file_digests", 16, Decodable::decode)?,
+                root_id: d.read_struct_field("root_id", 17, Decodable::decode)?,
+                timestamp: d.read_struct_field("timestamp", 18, |d| {
+                    let (secs, nanos) = <(u64, u32)>::decode(d)?;
+                    Ok(UNIX_EPOCH + Duration::new(secs, nanos))
+                })?,
+                path: d.read_struct_field("path", 19, Decodable::decode)?,
+                global_crate_num: d.read_struct_field("global_crate_num", 20, Decodable::decode)?,
+            };
+            per_crate.index_spans();
+            Ok(per_crate)
+        })
+    }
+}
+
+/// Encodes values as a sequence of little-endian integers. Strings, sequences
+/// and maps are prefixed with their length; enum variants and options with
+/// their index. Struct and field names are not recorded.
+struct BinaryEncoder<'a> {
+    buf: &'a mut Vec<u8>,
+}
+
+macro_rules! emit_le {
+    ($($name: ident: $ty: ty, $bytes: expr;)*) => {
+        $(fn $name(&mut self, v: $ty) -> CacheResult<()> {
+            let mut v = v as u64;
+            for _ in 0..$bytes {
+                self.buf.push(v as u8);
+                v >>= 8;
+            }
+            Ok(())
+        })*
+    }
+}
+
+impl<'a> Encoder for BinaryEncoder<'a> {
+    type Error = String;
+
+    emit_le! {
+        emit_usize: usize, 8;
+        emit_u64: u64, 8;
+        emit_u32: u32, 4;
+        emit_u16: u16, 2;
+        emit_u8: u8, 1;
+        emit_isize: isize, 8;
+        emit_i64: i64, 8;
+        emit_i32: i32, 4;
+        emit_i16: i16, 2;
+        emit_i8: i8, 1;
+    }
+
+    fn emit_nil(&mut self) -> CacheResult<()> {
+        Ok(())
+    }
+
+    fn emit_bool(&mut self, v: bool) -> CacheResult<()> {
+        self.emit_u8(v as u8)
+    }
+
+    fn emit_f64(&mut self, v: f64) -> CacheResult<()> {
+        self.emit_u64(v.to_bits())
+    }
+
+    fn emit_f32(&mut self, v: f32) -> CacheResult<()> {
+        self.emit_u32(v.to_bits())
+    }
+
+    fn emit_char(&mut self, v: char) -> CacheResult<()> {
+        self.emit_u32(v as u32)
+    }
+
+    fn emit_str(&mut self, v: &str) -> CacheResult<()> {
+        self.emit_usize(v.len())?;
+        self.buf.extend_from_slice(v.as_bytes());
+        Ok(())
+    }
+
+    fn emit_enum<F>(&mut self, _name: &str, f: F) -> CacheResult<()>
+    where F: FnOnce(&mut Self) -> CacheResult<()> {
+        f(self)
+    }
+
+    fn emit_enum_variant<F>(&mut self, _name: &str, id: usize, _len: usize, f: F) -> CacheResult<()>
+    where F: FnOnce(&mut Self) -> CacheResult<()> {
+        self.emit_u32(id as u32)?;
+        f(self)
+    }
+
+    fn emit_enum_variant_arg<F>(&mut self, _idx: usize, f: F) -> CacheResult<()>
+    where F: FnOnce(&mut Self) -> CacheResult<()> {
+        f(self)
+    }
+
+    fn emit_enum_struct_variant<F>(
+        &mut self,
+        name: &str,
+        id: usize,
+        len: usize,
+        f: F,
+    ) -> CacheResult<()>
+    where F: FnOnce(&mut Self) -> CacheResult<()> {
+        self.emit_enum_variant(name, id, len, f)
+    }
+
+    fn emit_enum_struct_variant_field<F>(
+        &mut self,
+        _name: &str,
+        _idx: usize,
+        f: F,
+    ) -> CacheResult<()>
+    where F: FnOnce(&mut Self) -> CacheResult<()> {
+        f(self)
+    }
+
+    fn emit_struct<F>(&mut self, _name: &str, _len: usize, f: F) -> CacheResult<()>
+    where F: FnOnce(&mut Self) -> CacheResult<()> {
+        f(self)
+    }
+
+    fn emit_struct_field<F>(&mut self, _name: &str, _idx: usize, f: F) -> CacheResult<()>
+    where F: FnOnce(&mut Self) -> CacheResult<()> {
+        f(self)
+    }
+
+    fn emit_tuple<F>(&mut self, _len: usize, f: F) -> CacheResult<()>
+    where F: FnOnce(&mut Self) -> CacheResult<()> {
+        f(self)
+    }
+
+    fn emit_tuple_arg<F>(&mut self, _idx: usize, f: F) -> CacheResult<()>
+    where F: FnOnce(&mut Self) -> CacheResult<()> {
+        f(self)
+    }
+
+    fn emit_tuple_struct<F>(&mut self, _name: &str, _len: usize, f: F) -> CacheResult<()>
+    where F: FnOnce(&mut Self) -> CacheResult<()> {
+        f(self)
+    }
+
+    fn emit_tuple_struct_arg<F>(&mut self, _idx: usize, f: F) -> CacheResult<()>
+    where F: FnOnce(&mut Self) -> CacheResult<()> {
+        f(self)
+    }
+
+    fn emit_option<F>(&mut self, f: F) -> CacheResult<()>
+    where F: FnOnce(&mut Self) -> CacheResult<()> {
+        f(self)
+    }
+
+    fn emit_option_none(&mut self) -> CacheResult<()> {
+        self.emit_u8(0)
+    }
+
+    fn emit_option_some<F>(&mut self, f: F) -> CacheResult<()>
+    where F: FnOnce(&mut Self) -> CacheResult<()> {
+        self.emit_u8(1)?;
+        f(self)
+    }
+
+    fn emit_seq<F>(&mut self, len: usize, f: F) -> CacheResult<()>
+    where F: FnOnce(&mut Self) -> CacheResult<()> {
+        self.emit_usize(len)?;
+        f(self)
+    }
+
+    fn emit_seq_elt<F>(&mut self, _idx: usize, f: F) -> CacheResult<()>
+    where F: FnOnce(&mut Self) -> CacheResult<()> {
+        f(self)
+    }
+
+    fn emit_map<F>(&mut self, len: usize, f: F) -> CacheResult<()>
+    where F: FnOnce(&mut Self) -> CacheResult<()> {
+        self.emit_usize(len)?;
+        f(self)
+    }
+
+    fn emit_map_elt_key<F>(&mut self, _idx: usize, f: F) -> CacheResult<()>
+    where F: FnOnce(&mut Self) -> CacheResult<()> {
+        f(self)
+    }
+
+    fn emit_map_elt_val<F>(&mut self, _idx: usize, f: F) -> CacheResult<()>
+    where F: FnOnce(&mut Self) -> CacheResult<()> {
+        f(self)
+    }
+}
+
+/// Decodes values written by `BinaryEncoder`.
+struct BinaryDecoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> BinaryDecoder<'a> {
+    fn read_bytes(&mut self, len: usize) -> CacheResult<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err("unexpected end of analysis cache".to_owned());
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn read_le(&mut self, len: usize) -> CacheResult<u64> {
+        let bytes = self.read_bytes(len)?;
+        Ok(bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64))
+    }
+
+    // The length of a sequence or map. Every element takes at least one byte,
+    // so a length greater than the bytes left means the cache is corrupt (and
+    // trusting it could make the decoder try to allocate far too much).
+    fn read_len(&mut self) -> CacheResult<usize> {
+        let len = self.read_usize()?;
+        if len > self.buf.len() {
+            return Err(format!("invalid length {} in analysis cache", len));
+        }
+        Ok(len)
+    }
+}
+
+macro_rules! read_le {
+    ($($name: ident: $ty: ty, $bytes: expr;)*) => {
+        $(fn $name(&mut self) -> CacheResult<$ty> {
+            self.read_le($bytes).map(|v| v as $ty)
+        })*
+    }
+}
+
+impl<'a> Decoder for BinaryDecoder<'a> {
+    type Error = String;
+
+    read_le! {
+        read_usize: usize, 8;
+        read_u64: u64, 8;
+        read_u32: u32, 4;
+        read_u16: u16, 2;
+        read_u8: u8, 1;
+        read_isize: isize, 8;
+        read_i64: i64, 8;
+        read_i32: i32, 4;
+        read_i16: i16, 2;
+        read_i8: i8, 1;
+    }
+
+    fn read_nil(&mut self) -> CacheResult<()> {
+        Ok(())
+    }
+
+    fn read_bool(&mut self) -> CacheResult<bool> {
+        self.read_u8().map(|v| v != 0)
+    }
+
+    fn read_f64(&mut self) -> CacheResult<f64> {
+        self.read_u64().map(f64::from_bits)
+    }
+
+    fn read_f32(&mut self) -> CacheResult<f32> {
+        self.read_u32().map(f32::from_bits)
+    }
+
+    fn read_char(&mut self) -> CacheResult<char> {
+        let v = self.read_u32()?;
+        ::std::char::from_u32(v).ok_or_else(|| format!("invalid char: {}", v))
+    }
+
+    fn read_str(&mut self) -> CacheResult<String> {
+        let len = self.read_usize()?;
+        let bytes = self.read_bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|err| err.to_string())
+    }
+
+    fn read_enum<T, F>(&mut self, _name: &str, f: F) -> CacheResult<T>
+    where F: FnOnce(&mut Self) -> CacheResult<T> {
+        f(self)
+    }
+
+    fn read_enum_variant<T, F>(&mut self, names: &[&str], mut f: F) -> CacheResult<T>
+    where F: FnMut(&mut Self, usize) -> CacheResult<T> {
+        let id = self.read_u32()? as usize;
+        if id >= names.len() {
+            return Err(format!("invalid variant index: {}", id));
+        }
+        f(self, id)
+    }
+
+    fn read_enum_variant_arg<T, F>(&mut self, _idx: usize, f: F) -> CacheResult<T>
+    where F: FnOnce(&mut Self) -> CacheResult<T> {
+        f(self)
+    }
+
+    fn read_enum_struct_variant<T, F>(&mut self, names: &[&str], f: F) -> CacheResult<T>
+    where F: FnMut(&mut Self, usize) -> CacheResult<T> {
+        self.read_enum_variant(names, f)
+    }
+
+    fn read_enum_struct_variant_field<T, F>(
+        &mut self,
+        _name: &str,
+        _idx: usize,
+        f: F,
+    ) -> CacheResult<T>
+    where F: FnOnce(&mut Self) -> CacheResult<T> {
+        f(self)
+    }
+
+    fn read_struct<T, F>(&mut self, _name: &str, _len: usize, f: F) -> CacheResult<T>
+    where F: FnOnce(&mut Self) -> CacheResult<T> {
+        f(self)
+    }
+
+    fn read_struct_field<T, F>(&mut self, _name: &str, _idx: usize, f: F) -> CacheResult<T>
+    where F: FnOnce(&mut Self) -> CacheResult<T> {
+        f(self)
+    }
+
+    fn read_tuple<T, F>(&mut self, _len: usize, f: F) -> CacheResult<T>
+    where F: FnOnce(&mut Self) -> CacheResult<T> {
+        f(self)
+    }
+
+    fn read_tuple_arg<T, F>(&mut self, _idx: usize, f: F) -> CacheResult<T>
+    where F: FnOnce(&mut Self) -> CacheResult<T> {
+        f(self)
+    }
+
+    fn read_tuple_struct<T, F>(&mut self, _name: &str, _len: usize, f: F) -> CacheResult<T>
+    where F: FnOnce(&mut Self) -> CacheResult<T> {
+        f(self)
+    }
+
+    fn read_tuple_struct_arg<T, F>(&mut self, _idx: usize, f: F) -> CacheResult<T>
+    where F: FnOnce(&mut Self) -> CacheResult<T> {
+        f(self)
+    }
+
+    fn read_option<T, F>(&mut self, mut f: F) -> CacheResult<T>
+    where F: FnMut(&mut Self, bool) -> CacheResult<T> {
+        let is_some = self.read_bool()?;
+        f(self, is_some)
+    }
+
+    fn read_seq<T, F>(&mut self, f: F) -> CacheResult<T>
+    where F: FnOnce(&mut Self, usize) -> CacheResult<T> {
+        let len = self.read_len()?;
+        f(self, len)
+    }
+
+    fn read_seq_elt<T, F>(&mut self, _idx: usize, f: F) -> CacheResult<T>
+    where F: FnOnce(&mut Self) -> CacheResult<T> {
+        f(self)
+    }
+
+    fn read_map<T, F>(&mut self, f: F) -> CacheResult<T>
+    where F: FnOnce(&mut Self, usize) -> CacheResult<T> {
+        let len = self.read_len()?;
+        f(self, len)
+    }
+
+    fn read_map_elt_key<T, F>(&mut self, _idx: usize, f: F) -> CacheResult<T>
+    where F: FnOnce(&mut Self) -> CacheResult<T> {
+        f(self)
+    }
+
+    fn read_map_elt_val<T, F>(&mut self, _idx: usize, f: F) -> CacheResult<T>
+    where F: FnOnce(&mut Self) -> CacheResult<T> {
+        f(self)
+    }
+
+    fn error(&mut self, err: &str) -> String {
+        err.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<T: Encodable + Decodable>(value: &T) -> T {
+        let mut buf = vec![];
+        value.encode(&mut BinaryEncoder { buf: &mut buf }).unwrap();
+        let mut decoder = BinaryDecoder { buf: &buf };
+        let result = T::decode(&mut decoder).unwrap();
+        assert!(decoder.buf.is_empty());
+        result
+    }
+
+    #[test]
+    fn test_round_trip() {
+        assert_eq!(round_trip(&0xdead_beef_u32), 0xdead_beef);
+        assert_eq!(round_trip(&-42i64), -42);
+        assert_eq!(round_trip(&1.5f64), 1.5);
+        assert_eq!(round_trip(&'λ'), 'λ');
+        assert_eq!(round_trip(&"hello".to_owned()), "hello");
+        assert_eq!(round_trip(&Some(vec![1u8, 2, 3])), Some(vec![1, 2, 3]));
+        assert_eq!(round_trip(&None::<String>), None);
+
+        let map: HashMap<String, Vec<u64>> =
+            vec![("a".to_owned(), vec![1]), ("b".to_owned(), vec![])].into_iter().collect();
+        assert_eq!(round_trip(&map), map);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut buf = vec![];
+        "hello".encode(&mut BinaryEncoder { buf: &mut buf }).unwrap();
+        buf.pop();
+        assert!(String::decode(&mut BinaryDecoder { buf: &buf }).is_err());
+    }
+}
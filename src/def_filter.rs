@@ -0,0 +1,90 @@
+// Copyright 2018 The RLS Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use analysis::{Def, PerCrateAnalysis};
+use raw::DefKind;
+use Id;
+
+use std::path::{Path, PathBuf};
+
+/// `DefFilter` specifies the predicate for enumerating defs by their kind,
+/// crate, location and parent, rather than by name (see `SymbolQuery`).
+///
+/// A def must satisfy every criterion which has been set; a new filter matches
+/// all defs. For example, all traits in the `foo` and `bar` crates are matched
+/// by `DefFilter::new().kinds(&[DefKind::Trait]).crates(&["foo", "bar"])`.
+#[derive(Debug, Clone, Default)]
+pub struct DefFilter {
+    kinds: Option<Vec<DefKind>>,
+    crates: Option<Vec<String>>,
+    path_prefix: Option<PathBuf>,
+    distro_crate: Option<bool>,
+    parent: Option<Id>,
+}
+
+impl DefFilter {
+    pub fn new() -> DefFilter {
+        DefFilter::default()
+    }
+
+    /// Only defs of one of `kinds`.
+    pub fn kinds(self, kinds: &[DefKind]) -> DefFilter {
+        DefFilter { kinds: Some(kinds.to_owned()), ..self }
+    }
+
+    /// Only defs in a crate with one of the names in `crates`.
+    pub fn crates(self, crates: &[&str]) -> DefFilter {
+        DefFilter { crates: Some(crates.iter().map(|c| c.to_string()).collect()), ..self }
+    }
+
+    /// Only defs in files under `path_prefix`. Def spans have absolute paths,
+    /// so `path_prefix` should be absolute too.
+    pub fn path_prefix(self, path_prefix: &Path) -> DefFilter {
+        DefFilter { path_prefix: Some(path_prefix.to_owned()), ..self }
+    }
+
+    /// Only defs in (if `distro_crate` is true), or not in, the Rust distro
+    /// crates, e.g., std.
+    pub fn distro_crate(self, distro_crate: bool) -> DefFilter {
+        DefFilter { distro_crate: Some(distro_crate), ..self }
+    }
+
+    /// Only children of the def `parent`.
+    pub fn parent(self, parent: Id) -> DefFilter {
+        DefFilter { parent: Some(parent), ..self }
+    }
+
+    // The names of the crates to search, or `None` to search all crates.
+    crate fn crate_names(&self) -> Option<&[String]> {
+        self.crates.as_ref().map(|crates| &crates[..])
+    }
+
+    // The ids of defs in `analysis` which might match, taken from the
+    // narrowest index we have for the filter.
+    crate fn candidates(&self, analysis: &PerCrateAnalysis) -> Vec<Id> {
+        if let Some(parent) = self.parent {
+            return analysis.children.get(&parent).map_or(vec![], |c| c.iter().cloned().collect());
+        }
+        match self.kinds {
+            Some(ref kinds) => analysis.defs_per_kind
+                .iter()
+                .filter(|&&(kind, _)| kinds.contains(&kind))
+                .flat_map(|&(_, ref ids)| ids.iter().cloned())
+                .collect(),
+            None => analysis.defs.keys().cloned().collect(),
+        }
+    }
+
+    // Whether a candidate def matches. The crate and parent are taken into
+    // account by `candidates`, not here.
+    crate fn matches(&self, def: &Def) -> bool {
+        self.kinds.as_ref().map_or(true, |kinds| kinds.contains(&def.kind))
+            && self.distro_crate.map_or(true, |d| d == def.distro_crate)
+            && self.path_prefix.as_ref().map_or(true, |prefix| def.span.file.starts_with(prefix))
+    }
+}
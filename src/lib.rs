@@ -17,28 +17,44 @@ extern crate rls_data as data;
 extern crate rls_span as span;
 extern crate rustc_serialize;
 extern crate fst;
+extern crate fst_levenshtein;
+extern crate fst_regex;
 extern crate itertools;
 extern crate json;
+extern crate rayon;
 
 mod analysis;
+mod cache;
 mod raw;
 mod loader;
 mod lowering;
 mod listings;
 mod util;
 mod symbol_query;
+mod def_filter;
+mod outline;
+mod rename;
+mod watcher;
 #[cfg(test)]
 mod test;
 
-pub use analysis::{Def, Ref};
-use analysis::Analysis;
-pub use raw::{name_space_for_def_kind, read_analysis_from_files, CrateId, DefKind};
+pub use analysis::{Attribute, ChangeEvent, ChangeSet, CrateChange, Def, Impl, Import, Ref,
+                   RefKind, SigElement, Signature};
+use analysis::{Analysis, PerCrateAnalysis, RelationKind};
+pub use raw::{name_space_for_def_kind, read_analysis_from_files, CrateId, DefKind, ImportKind};
 pub use loader::{AnalysisLoader, CargoAnalysisLoader, SearchDirectory, Target};
-pub use symbol_query::SymbolQuery;
-
-use std::collections::HashMap;
+pub use symbol_query::{Cursor, SymbolQuery};
+pub use def_filter::DefFilter;
+pub use outline::{OutlineItem, OutlineNode};
+pub use rename::{RenamePlan, RenameRefusal, RenameRefusalReason};
+pub use watcher::Watcher;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::{Instant, SystemTime};
 use std::u64;
 
@@ -47,16 +63,64 @@ pub struct AnalysisHost<L: AnalysisLoader = CargoAnalysisLoader> {
     analysis: Mutex<Option<Analysis>>,
     master_crate_map: Mutex<HashMap<CrateId, u32>>,
     loader: Mutex<L>,
+    // The changes made by the most recent reload, if `track_changes` is set.
+    changes: Mutex<Vec<ChangeSet>>,
+    track_changes: AtomicBool,
+    subscribers: Mutex<Vec<Subscriber>>,
+    next_subscription: AtomicUsize,
+}
+
+/// Identifies a callback registered with `AnalysisHost::subscribe`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(usize);
+
+struct Subscriber {
+    id: SubscriptionId,
+    callback: Arc<dyn Fn(&ChangeEvent) + Send + Sync>,
+}
+
+impl fmt::Debug for Subscriber {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Subscriber({:?})", self.id)
+    }
 }
 
 pub type AResult<T> = Result<T, AError>;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AError {
     MutexPoison,
-    Unclassified,
+    /// No analysis data has been loaded yet.
+    NotLoaded,
+    /// There is no def or ref at the given span.
+    NoDefAtSpan(Span),
+    /// The id does not belong to any def in the loaded crates.
+    UnknownId(Id),
+    /// No defs have been recorded for the given file.
+    NoDefsInFile(PathBuf),
+    /// The def exists, but it has no documentation.
+    NoDocs,
+    /// The def exists, but the save-analysis data has no signature for it.
+    NoSignature,
+    /// The def is not part of the Rust distribution, so it has no doc or
+    /// source url.
+    NotDistroCrate,
+    /// The def is in a distro crate, but we could not build a url for it.
+    NoUrl,
+    /// An IO error, e.g., when reading or writing a cache file.
+    Io(io::ErrorKind),
+    /// A symbol query pattern (e.g., a regex) could not be parsed.
+    InvalidQuery(String),
+    /// The cache file is corrupt or was written by a different version.
+    InvalidCache,
+    /// A rename would not be safe, see `AnalysisHost::plan_rename`.
+    RenameRefused(RenameRefusal),
+    /// The module which a glob import imports from is not known, so the glob
+    /// can't be expanded.
+    UnknownGlobModule(Span),
 }
 
+/// A def found by one of the search APIs, e.g., `query_symbols` or `symbols`.
 #[derive(Debug, Clone)]
 pub struct SymbolResult {
     pub id: Id,
@@ -64,16 +128,30 @@ pub struct SymbolResult {
     pub kind: raw::DefKind,
     pub span: Span,
     pub parent: Option<Id>,
+    /// The crate which owns the def.
+    pub krate: CrateId,
+    /// Byte ranges of `name` which matched the query, for highlighting.
+    /// Empty if the search was not by name, or the query can't say which
+    /// parts of the name matched (see `SymbolQuery::match_ranges`).
+    pub ranges: Vec<(usize, usize)>,
+    /// How well the def matched the query, higher is better (see
+    /// `SymbolQuery::score`). Zero if the search was not by name.
+    pub rank: u32,
+    pub def: Def,
 }
 
 impl SymbolResult {
-    fn new(id: Id, def: &Def) -> SymbolResult {
+    fn new(id: Id, def: &Def, krate: &CrateId) -> SymbolResult {
         SymbolResult {
             id,
             name: def.name.clone(),
             span: def.span.clone(),
             kind: def.kind,
             parent: def.parent,
+            krate: krate.clone(),
+            ranges: vec![],
+            rank: 0,
+            def: def.clone(),
         }
     }
 }
@@ -83,7 +161,8 @@ pub type Span = span::Span<span::ZeroIndexed>;
 /// A common identifier for definitions, references etc. This is effectively a
 /// `DefId` with globally unique crate number (instead of a compiler generated
 /// crate-local number).
-#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash, new)]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash, new, RustcEncodable,
+         RustcDecodable)]
 pub struct Id(u64);
 
 impl Id {
@@ -105,7 +184,7 @@ macro_rules! clone_field {
 
 macro_rules! def_span {
     ($analysis: expr, $id: expr) => {
-        $analysis.with_defs_and_then($id, |def| Some(def.span.clone()))
+        $analysis.with_defs($id, |def| def.span.clone())
     }
 }
 
@@ -115,6 +194,10 @@ impl AnalysisHost<CargoAnalysisLoader> {
             analysis: Mutex::new(None),
             master_crate_map: Mutex::new(HashMap::new()),
             loader: Mutex::new(CargoAnalysisLoader::new(target)),
+            changes: Mutex::new(vec![]),
+            track_changes: AtomicBool::new(false),
+            subscribers: Mutex::new(vec![]),
+            next_subscription: AtomicUsize::new(0),
         }
     }
 }
@@ -125,6 +208,10 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
             analysis: Mutex::new(None),
             master_crate_map: Mutex::new(HashMap::new()),
             loader: Mutex::new(loader),
+            changes: Mutex::new(vec![]),
+            track_changes: AtomicBool::new(false),
+            subscribers: Mutex::new(vec![]),
+            next_subscription: AtomicUsize::new(0),
         }
     }
 
@@ -144,16 +231,12 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
             .map(|analysis| raw::Crate::new(analysis, SystemTime::now(), None, None))
             .collect();
 
-        lowering::lower(
-            crates,
-            base_dir,
-            self,
-            |host, per_crate, id| {
-                let mut a = host.analysis.lock()?;
-                a.as_mut().unwrap().update(id, per_crate);
-                Ok(())
-            },
-        )
+        let mut lowered = vec![];
+        lowering::lower(crates, base_dir, self, |_, per_crate, id| {
+            lowered.push((id, per_crate));
+            Ok(())
+        })?;
+        self.update(lowered, false)
     }
 
     pub fn reload(&self, path_prefix: &Path, base_dir: &Path) -> AResult<()> {
@@ -172,6 +255,7 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
             base_dir,
             blacklist
         );
+        self.changes.lock()?.clear();
         let empty = self.analysis.lock()?.is_none();
         if empty || self.loader.lock()?.needs_hard_reload(path_prefix) {
             return self.hard_reload_with_blacklist(path_prefix, base_dir, blacklist);
@@ -183,11 +267,13 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
             read_analysis_from_files(&*loader, timestamps, blacklist)
         };
 
-        lowering::lower(raw_analysis, base_dir, self, |host, per_crate, id| {
-            let mut a = host.analysis.lock()?;
-            a.as_mut().unwrap().update(id, per_crate);
+        // Lower the new data alongside ours, then swap it in all at once.
+        let mut lowered = vec![];
+        lowering::lower(raw_analysis, base_dir, self, |_, per_crate, id| {
+            lowered.push((id, per_crate));
             Ok(())
-        })
+        })?;
+        self.update(lowered, true)
     }
 
     /// Reloads the entire project's analysis data.
@@ -221,6 +307,16 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
             })?;
         }
 
+        // The fresh data was lowered from scratch, so compare it with ours to
+        // find what changed.
+        let changes = if self.tracking_changes()? {
+            let analysis = self.analysis.lock()?;
+            let fresh_analysis = fresh_host.analysis.lock()?;
+            Analysis::changes(analysis.as_ref(), fresh_analysis.as_ref().unwrap())
+        } else {
+            vec![]
+        };
+
         // To guarantee a consistent state and no corruption in case an error
         // happens during reloading, we need to swap data with a dummy host in
         // a single atomic step. We can't lock and swap every member at a time,
@@ -236,7 +332,143 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
             };
         }
 
-        swap_mutex_fields!(analysis, master_crate_map, loader);
+        {
+            swap_mutex_fields!(analysis, master_crate_map, loader);
+        }
+        self.notify(&changes)?;
+        *self.changes.lock()? = changes;
+
+        Ok(())
+    }
+
+    /// Registers `callback` to be called with each change to the loaded data,
+    /// as it happens during a reload. The callback is called with no locks
+    /// held, so it may query this host, or call `subscribe` or `unsubscribe`
+    /// (which take effect from the next reload).
+    ///
+    /// To receive events on a channel instead, send them from the callback.
+    pub fn subscribe<F>(&self, callback: F) -> AResult<SubscriptionId>
+    where
+        F: Fn(&ChangeEvent) + Send + Sync + 'static,
+    {
+        let id = SubscriptionId(self.next_subscription.fetch_add(1, Ordering::Relaxed));
+        self.subscribers.lock()?.push(Subscriber { id, callback: Arc::new(callback) });
+        Ok(id)
+    }
+
+    pub fn unsubscribe(&self, id: SubscriptionId) -> AResult<()> {
+        self.subscribers.lock()?.retain(|s| s.id != id);
+        Ok(())
+    }
+
+    fn notify(&self, changes: &[ChangeSet]) -> AResult<()> {
+        // Don't hold the lock while calling back, callbacks may (un)subscribe.
+        let callbacks: Vec<_> =
+            self.subscribers.lock()?.iter().map(|s| s.callback.clone()).collect();
+        if callbacks.is_empty() {
+            return Ok(());
+        }
+        for event in changes.iter().flat_map(|c| c.events()) {
+            for callback in &callbacks {
+                callback(&event);
+            }
+        }
+        Ok(())
+    }
+
+    /// The changes made by the most recent reload, one `ChangeSet` for each
+    /// crate which was added, removed or changed. Empty unless changes are
+    /// being tracked, see `track_changes`.
+    pub fn changes(&self) -> AResult<Vec<ChangeSet>> {
+        Ok(self.changes.lock()?.clone())
+    }
+
+    /// Whether to record the changes made by each reload for `changes`.
+    /// Finding the changes means comparing each reloaded crate with the data
+    /// it replaces, so it is off by default. Changes are also found whenever
+    /// there are subscribers.
+    pub fn track_changes(&self, track: bool) {
+        self.track_changes.store(track, Ordering::Relaxed);
+    }
+
+    // Whether to find what each reload changes, see `track_changes`.
+    fn tracking_changes(&self) -> AResult<bool> {
+        Ok(self.track_changes.load(Ordering::Relaxed) || !self.subscribers.lock()?.is_empty())
+    }
+
+    // Removes any crates whose save-analysis file has been deleted (if
+    // `remove_missing`) and adds or replaces the crates in `lowered`, under a
+    // single lock so that queries see either the old data or the new, never a
+    // mix. Removed crates keep their numbers in the master crate map, since
+    // the ids of defs in other crates which refer to them include it, and so
+    // that they get the same number if they come back.
+    fn update(
+        &self,
+        lowered: Vec<(CrateId, PerCrateAnalysis)>,
+        remove_missing: bool,
+    ) -> AResult<()> {
+        let tracking = self.tracking_changes()?;
+        let changes = {
+            let mut a = self.analysis.lock()?;
+            let a = a.as_mut().unwrap();
+            let mut changes = vec![];
+            if remove_missing {
+                for (id, per_crate) in a.remove_stale_crates(false) {
+                    if tracking {
+                        changes.push(ChangeSet::new(id, Some(&per_crate), None));
+                    }
+                }
+            }
+            for (id, per_crate) in lowered {
+                let old = a.update(id.clone(), per_crate);
+                if tracking {
+                    changes.push(ChangeSet::new(id.clone(), old.as_ref(), a.per_crate.get(&id)));
+                }
+            }
+            changes.retain(|c| !c.is_empty());
+            changes
+        };
+        self.notify(&changes)?;
+        self.changes.lock()?.extend(changes);
+        Ok(())
+    }
+
+    /// Writes a snapshot of the loaded analysis data to `path`, which can be
+    /// restored by `load_cache`.
+    pub fn save_cache(&self, path: &Path) -> AResult<()> {
+        let analysis = self.analysis.lock()?;
+        let analysis = analysis.as_ref().ok_or(AError::NotLoaded)?;
+        let master_crate_map = self.master_crate_map.lock()?;
+        cache::write(path, analysis, &master_crate_map)
+    }
+
+    /// Replaces the loaded analysis data with a snapshot written by
+    /// `save_cache`. Crates whose save-analysis files have been modified or
+    /// removed since they were read are dropped, so a subsequent `reload` with
+    /// the same `path_prefix` only needs to read and lower those crates.
+    pub fn load_cache(&self, path: &Path, path_prefix: &Path) -> AResult<()> {
+        let (mut fresh_analysis, fresh_crate_map) = cache::read(path)?;
+        fresh_analysis.remove_stale_crates(true);
+
+        // As for `update`, the changes are found under the same lock as the
+        // swap, and subscribers are notified once it is released.
+        let tracking = self.tracking_changes()?;
+        let changes = {
+            let mut analysis = self.analysis.lock()?;
+            let mut master_crate_map = self.master_crate_map.lock()?;
+            let mut loader = self.loader.lock()?;
+            let changes = if tracking {
+                Analysis::changes(analysis.as_ref(), &fresh_analysis)
+            } else {
+                vec![]
+            };
+            *analysis = Some(fresh_analysis);
+            *master_crate_map = fresh_crate_map;
+            loader.set_path_prefix(path_prefix);
+            changes
+        };
+        self.notify(&changes)?;
+        *self.changes.lock()? = changes;
 
         Ok(())
     }
@@ -254,32 +486,65 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
         self.with_analysis(|a| a.with_defs(id, |def| def.clone()))
     }
 
+    /// Ids of all defs with an attribute named `name`, e.g., `test` for
+    /// `#[test]` functions.
+    pub fn defs_with_attribute(&self, name: &str) -> AResult<Vec<Id>> {
+        self.with_analysis(|a| {
+            Ok(a.for_all_crates(|c| {
+                Some(c.defs
+                    .iter()
+                    .filter(|&(_, def)| def.attributes.iter().any(|attr| attr.name() == name))
+                    .map(|(id, _)| *id)
+                    .collect())
+            }))
+        })
+    }
+
+    /// Whether the def has a `#[deprecated]` (or `#[rustc_deprecated]`)
+    /// attribute.
+    pub fn is_deprecated(&self, id: Id) -> AResult<bool> {
+        self.with_analysis(|a| {
+            a.with_defs(id, |def| {
+                def.attributes.iter().any(|attr| {
+                    attr.name() == "deprecated" || attr.name() == "rustc_deprecated"
+                })
+            })
+        })
+    }
+
     pub fn goto_def(&self, span: &Span) -> AResult<Span> {
         self.with_analysis(|a| a.def_id_for_span(span).and_then(|id| def_span!(a, id)))
     }
 
+    /// Like `goto_def`, but returns the spans of all the defs at `span` (see
+    /// `ids`).
+    pub fn goto_defs(&self, span: &Span) -> AResult<Vec<Span>> {
+        self.with_analysis(|a| {
+            a.def_ids_for_span(span).and_then(|ids| a.map_defs(&ids, |def| def.span.clone()))
+        })
+    }
+
     pub fn for_each_child_def<F, T>(&self, id: Id, f: F) -> AResult<Vec<T>>
     where
         F: FnMut(Id, &Def) -> T,
     {
-        self.with_analysis(|a| a.for_each_child(id, f))
+        self.with_analysis(|a| a.for_each_child(id, f).ok_or(AError::UnknownId(id)))
     }
 
     pub fn def_parents(&self, id: Id) -> AResult<Vec<(Id, String)>> {
         self.with_analysis(|a| {
+            a.with_defs(id, |_| ())?;
             let mut result = vec![];
             let mut next = id;
             loop {
-                match a.with_defs_and_then(next, |def| {
-                    def.parent
-                        .and_then(|p| a.with_defs(p, |def| (p, def.name.clone())))
-                }) {
+                let parent = a.with_defs(next, |def| def.parent).ok().and_then(|p| p);
+                match parent.and_then(|p| a.with_defs(p, |def| (p, def.name.clone())).ok()) {
                     Some((id, name)) => {
                         result.insert(0, (id, name));
                         next = id;
                     }
                     None => {
-                        return Some(result);
+                        return Ok(result);
                     }
                 }
             }
@@ -290,7 +555,7 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
     /// module of that crate.
     pub fn def_roots(&self) -> AResult<Vec<(Id, String)>> {
         self.with_analysis(|a| {
-            Some(
+            Ok(
                 a.per_crate
                 .iter()
                 .filter_map(|(crate_id, data)| {
@@ -305,6 +570,38 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
         self.with_analysis(|a| a.def_id_for_span(span))
     }
 
+    /// Like `id`, but returns the ids of all the defs at `span`. A ref may be
+    /// to more than one def, e.g., a field shorthand (`Foo { x }`) is a ref to
+    /// both the field and the variable, in which case `id` (and other queries
+    /// which need a single def) use the first of them, the field.
+    pub fn ids(&self, span: &Span) -> AResult<Vec<Id>> {
+        self.with_analysis(|a| a.def_ids_for_span(span))
+    }
+
+    /// Returns the id of the def or ref whose span covers the given position in
+    /// `file`. Where spans are nested, the innermost one is used.
+    pub fn id_at_position(
+        &self,
+        file: &Path,
+        row: span::Row<span::ZeroIndexed>,
+        col: span::Column<span::ZeroIndexed>,
+    ) -> AResult<Id> {
+        self.with_analysis(|a| {
+            a.ref_at_position(file, row, col).and_then(|(span, _)| a.def_id_for_span(&span))
+        })
+    }
+
+    /// Like id_at_position, but also returns the span which covers the
+    /// position, so it can be passed to `goto_def`, `show_type`, etc.
+    pub fn ref_at_position(
+        &self,
+        file: &Path,
+        row: span::Row<span::ZeroIndexed>,
+        col: span::Column<span::ZeroIndexed>,
+    ) -> AResult<(Span, Ref)> {
+        self.with_analysis(|a| a.ref_at_position(file, row, col))
+    }
+
     /// Like id, but will only return a value if it is in the same crate as span.
     pub fn crate_local_id(&self, span: &Span) -> AResult<Id> {
         self.with_analysis(|a| a.local_def_id_for_span(span))
@@ -317,33 +614,80 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
     // Note that for large numbers of refs, if `force_unique_spans` is true, then
     // this function might take significantly longer to execute.
     pub fn find_all_refs(&self, span: &Span, include_decl: bool, force_unique_spans: bool) -> AResult<Vec<Span>> {
+        self.find_refs(span, include_decl, force_unique_spans, None)
+    }
+
+    /// Like `find_all_refs`, but only returns the refs whose kind is one of
+    /// `kinds`, e.g., only type uses. The declaration (if `include_decl`) is
+    /// not a ref, so is not filtered.
+    pub fn find_all_refs_of_kind(
+        &self,
+        span: &Span,
+        include_decl: bool,
+        force_unique_spans: bool,
+        kinds: &[RefKind],
+    ) -> AResult<Vec<Span>> {
+        self.find_refs(span, include_decl, force_unique_spans, Some(kinds))
+    }
+
+    /// The kinds of the ref at `span`, to each of the defs which `ids` returns
+    /// for it (in the same order). A def's own span is not a ref, so has no
+    /// kinds.
+    pub fn ref_kinds(&self, span: &Span) -> AResult<Vec<(Id, RefKind)>> {
+        self.with_analysis(|a| {
+            let kinds: Vec<_> = a.def_ids_for_span(span)?
+                .into_iter()
+                .filter_map(|id| a.ref_kind(id, span).map(|kind| (id, kind)))
+                .collect();
+            if kinds.is_empty() {
+                return Err(AError::NoDefAtSpan(span.clone()));
+            }
+            Ok(kinds)
+        })
+    }
+
+    fn find_refs(
+        &self,
+        span: &Span,
+        include_decl: bool,
+        force_unique_spans: bool,
+        kinds: Option<&[RefKind]>,
+    ) -> AResult<Vec<Span>> {
         let t_start = Instant::now();
         let result = self.with_analysis(|a| {
-            a.def_id_for_span(span).map(|id| {
-                if force_unique_spans && a.aliased_imports.contains(&id) {
+            // If `span` is a ref to more than one def (e.g., a field shorthand),
+            // we find the refs to all of them.
+            a.def_ids_for_span(span).map(|ids| {
+                if force_unique_spans
+                    && (ids.len() > 1 || ids.iter().any(|&id| a.is_imported_with_alias(id)))
+                {
                     return vec![];
                 }
-                let decl = if include_decl {
-                    def_span!(a, id)
-                } else {
-                    None
-                };
-                let refs = a.with_ref_spans(id, |refs| {
+                let mut decls = vec![];
+                let mut refs = vec![];
+                for id in ids {
+                    let id_refs = match a.with_ref_spans(id, |refs| Some(refs.clone())) {
+                        Some(id_refs) => id_refs,
+                        None => continue,
+                    };
                     if force_unique_spans {
-                        for r in refs.iter() {
+                        for &(ref r, _) in id_refs.iter() {
                             match a.ref_for_span(r) {
-                                Some(Ref::Id(_)) => {},
-                                _ => return None,
+                                Ok(Ref::Id(_)) => {},
+                                _ => return vec![],
                             }
                         }
                     }
-                    Some(refs.clone())
-                });
-                refs.map(|refs| {
-                    decl.into_iter()
-                        .chain(refs.into_iter())
-                        .collect::<Vec<_>>()
-                }).unwrap_or_else(|| vec![])
+                    if include_decl {
+                        decls.extend(def_span!(a, id).ok());
+                    }
+                    refs.extend(id_refs.into_iter().filter(|&(_, kind)| match kinds {
+                        Some(kinds) => kinds.contains(&kind),
+                        None => true,
+                    }).map(|(span, _)| span));
+                }
+                let mut seen = HashSet::new();
+                decls.into_iter().chain(refs).filter(|s| seen.insert(s.clone())).collect()
             })
         });
 
@@ -355,18 +699,61 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
         result
     }
 
+    /// Plans renaming the def at `span` (the def's own span, or that of a ref
+    /// to it) to `new_name`. Unlike `find_all_refs`, if the rename is not safe
+    /// this returns `AError::RenameRefused`, saying why.
+    ///
+    /// The check for name collisions only covers the def's siblings (defs with
+    /// the same parent) in the def's own namespace. A new name may still clash
+    /// with, e.g., a glob import, a local variable or a def in a nested scope.
+    pub fn plan_rename(&self, span: &Span, new_name: &str) -> AResult<RenamePlan> {
+        self.with_analysis(|a| rename::plan(a, span, new_name))
+    }
+
+    /// The names imported by the glob import at `span` (the span of the `*`),
+    /// with the defs they refer to, sorted by name. Names are resolved among
+    /// the items and imports of the glob's module; any which can't be are left
+    /// out.
+    pub fn glob_expansion(&self, span: &Span) -> AResult<Vec<(String, Id)>> {
+        self.with_analysis(|a| a.glob_expansion(span))
+    }
+
+    /// The type of the def at `span`. If there is more than one def (see
+    /// `ids`), their distinct types, one per line.
     pub fn show_type(&self, span: &Span) -> AResult<String> {
+        self.with_analysis(|a| {
+            a.def_ids_for_span(span)
+                .and_then(|ids| a.map_defs(&ids, clone_field!(value)))
+                .map(|values| values.join("\n"))
+                .or_else(|_| a.with_globs(span, clone_field!(value)))
+        })
+    }
+
+    /// Returns the signature of the def at `span`, if the save-analysis data
+    /// included one.
+    pub fn signature(&self, span: &Span) -> AResult<Signature> {
         self.with_analysis(|a| {
             a.def_id_for_span(span)
-                .and_then(|id| a.with_defs(id, clone_field!(value)))
-                .or_else(|| a.with_globs(span, clone_field!(value)))
+                .and_then(|id| {
+                    a.with_defs_and_then(id, |def| def.sig.clone().ok_or(AError::NoSignature))
+                })
         })
     }
 
+    /// The docs of the def at `span`. If there is more than one def (see
+    /// `ids`), their distinct docs, separated by blank lines.
     pub fn docs(&self, span: &Span) -> AResult<String> {
         self.with_analysis(|a| {
-            a.def_id_for_span(span)
-                .and_then(|id| a.with_defs(id, clone_field!(docs)))
+            a.def_ids_for_span(span)
+                .and_then(|ids| a.map_defs(&ids, clone_field!(docs)))
+                .and_then(|docs| {
+                    let docs: Vec<_> = docs.into_iter().filter(|d| !d.is_empty()).collect();
+                    if docs.is_empty() {
+                        Err(AError::NoDocs)
+                    } else {
+                        Ok(docs.join("\n\n"))
+                    }
+                })
         })
     }
 
@@ -376,11 +763,17 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
     }
 
     pub fn query_defs(&self, query: SymbolQuery) -> AResult<Vec<Def>> {
+        self.query_symbols(query).map(|defs| defs.into_iter().map(|m| m.def).collect())
+    }
+
+    /// Like `query_defs`, but returns the id, crate, match ranges and score of
+    /// each def too.
+    pub fn query_symbols(&self, query: SymbolQuery) -> AResult<Vec<SymbolResult>> {
         let t_start = Instant::now();
         let result = self.with_analysis(move |a| {
             let defs = a.query_defs(query);
             info!("query_defs {:?}", &defs);
-            Some(defs)
+            Ok(defs)
         });
 
         let time = t_start.elapsed();
@@ -392,21 +785,35 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
         result
     }
 
+    /// Returns one page of the defs matching `query`, of (exactly, unless it
+    /// is the last page) `query`'s `limit` matches, and a cursor for the next
+    /// page if there are more matches. Pass `None` to get the first page.
+    ///
+    /// Each match appears on only one page, as long as the analysis is not
+    /// reloaded in between.
+    pub fn query_defs_page(
+        &self,
+        query: SymbolQuery,
+        cursor: Option<Cursor>,
+    ) -> AResult<(Vec<SymbolResult>, Option<Cursor>)> {
+        self.with_analysis(move |a| Ok(a.query_defs_page(query, cursor)))
+    }
+
     /// Search for a symbol name, returns a list of spans matching defs and refs
     /// for that name.
     pub fn search(&self, name: &str) -> AResult<Vec<Span>> {
         let t_start = Instant::now();
         let result = self.with_analysis(|a| {
-            Some(a.with_def_names(name, |defs| {
+            Ok(a.with_def_names(name, |defs| {
                 info!("defs: {:?}", defs);
                 defs.into_iter()
                     .flat_map(|id| {
                         a.with_ref_spans(*id, |refs| {
                             Some(def_span!(a, *id)
                                 .into_iter()
-                                .chain(refs.iter().cloned())
+                                .chain(refs.iter().map(|&(ref span, _)| span.clone()))
                                 .collect::<Vec<_>>())
-                        }).or_else(|| def_span!(a, *id).map(|s| vec![s]))
+                        }).or_else(|| def_span!(a, *id).ok().map(|s| vec![s]))
                             .unwrap_or_else(Vec::new)
                             .into_iter()
                     })
@@ -430,9 +837,9 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
             a.with_ref_spans(id, |refs| {
                 Some(def_span!(a, id)
                     .into_iter()
-                    .chain(refs.iter().cloned())
+                    .chain(refs.iter().map(|&(ref span, _)| span.clone()))
                     .collect::<Vec<_>>())
-            }).or_else(|| def_span!(a, id).map(|s| vec![s]))
+            }).map(Ok).unwrap_or_else(|| def_span!(a, id).map(|s| vec![s]))
         });
 
         let time = t_start.elapsed();
@@ -445,25 +852,98 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
 
     pub fn find_impls(&self, id: Id) -> AResult<Vec<Span>> {
         self.with_analysis(|a| {
-            Some(a.for_all_crates(|c| c.impls.get(&id).cloned()))
+            Ok(a.for_all_crates(|c| c.impls.get(&id).cloned()))
+        })
+    }
+
+    /// Ids of the types which implement the trait `id`.
+    pub fn implementors_of_trait(&self, id: Id) -> AResult<Vec<Id>> {
+        self.with_analysis(|a| {
+            Ok(a.related_ids(id, |r| if r.kind == RelationKind::Impl && r.to == id {
+                Some(r.from)
+            } else {
+                None
+            }))
+        })
+    }
+
+    /// Ids of the traits implemented by the type `id`.
+    pub fn traits_implemented_by(&self, id: Id) -> AResult<Vec<Id>> {
+        self.with_analysis(|a| {
+            Ok(a.related_ids(id, |r| if r.kind == RelationKind::Impl && r.from == id {
+                Some(r.to)
+            } else {
+                None
+            }))
+        })
+    }
+
+    /// Ids of the traits which the trait `id` directly inherits from.
+    pub fn supertraits(&self, id: Id) -> AResult<Vec<Id>> {
+        self.with_analysis(|a| {
+            Ok(a.related_ids(id, |r| if r.kind == RelationKind::SuperTrait && r.to == id {
+                Some(r.from)
+            } else {
+                None
+            }))
+        })
+    }
+
+    /// Ids of the traits which directly inherit from the trait `id`.
+    pub fn subtraits(&self, id: Id) -> AResult<Vec<Id>> {
+        self.with_analysis(|a| {
+            Ok(a.related_ids(id, |r| if r.kind == RelationKind::SuperTrait && r.from == id {
+                Some(r.to)
+            } else {
+                None
+            }))
+        })
+    }
+
+    /// The functions and methods which call the function or method `id`, each
+    /// with the spans of its calls.
+    pub fn incoming_calls(&self, id: Id) -> AResult<Vec<(Id, Vec<Span>)>> {
+        self.with_analysis(|a| {
+            Ok(a.related_calls(id, |c| if c.callee == id { Some(c.caller) } else { None }))
+        })
+    }
+
+    /// The functions and methods called by the function or method `id`, each
+    /// with the spans of the calls.
+    pub fn outgoing_calls(&self, id: Id) -> AResult<Vec<(Id, Vec<Span>)>> {
+        self.with_analysis(|a| {
+            Ok(a.related_calls(id, |c| if c.caller == id { Some(c.callee) } else { None }))
         })
     }
 
     /// Search for a symbol name, returning a list of def_ids for that name.
     pub fn search_for_id(&self, name: &str) -> AResult<Vec<Id>> {
-        self.with_analysis(|a| Some(a.with_def_names(name, |defs| defs.clone())))
+        self.with_analysis(|a| Ok(a.with_def_names(name, |defs| defs.clone())))
     }
 
     pub fn symbols(&self, file_name: &Path) -> AResult<Vec<SymbolResult>> {
-        self.with_analysis(|a| {
-            a.with_defs_per_file(file_name, |ids| {
-                ids.iter()
-                    .map(|id| {
-                        a.with_defs(*id, |def| SymbolResult::new(*id, def)).unwrap()
-                    })
-                    .collect()
-            })
-        })
+        self.with_analysis(|a| a.symbols(file_name))
+    }
+
+    /// The modules, types, impls, functions, fields, etc. in a file, as a tree
+    /// (e.g., methods are nested in their impl or trait) in source order.
+    pub fn outline(&self, file_name: &Path) -> AResult<Vec<OutlineNode>> {
+        self.with_analysis(|a| a.outline(file_name))
+    }
+
+    /// The imports in `file`, in source order.
+    pub fn imports_in_file(&self, file: &Path) -> AResult<Vec<Import>> {
+        self.with_analysis(|a| a.imports_in_file(file))
+    }
+
+    /// The imports of the def `id`, in any crate.
+    pub fn importers_of(&self, id: Id) -> AResult<Vec<Import>> {
+        self.with_analysis(|a| Ok(a.importers_of(id)))
+    }
+
+    /// All defs which match `filter`.
+    pub fn filter_defs(&self, filter: &DefFilter) -> AResult<Vec<SymbolResult>> {
+        self.with_analysis(|a| Ok(a.filter_defs(filter)))
     }
 
     pub fn doc_url(&self, span: &Span) -> AResult<String> {
@@ -491,19 +971,19 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
 
     fn with_analysis<F, T>(&self, f: F) -> AResult<T>
     where
-        F: FnOnce(&Analysis) -> Option<T>,
+        F: FnOnce(&Analysis) -> AResult<T>,
     {
         let a = self.analysis.lock()?;
         if let Some(ref a) = *a {
-            f(a).ok_or(AError::Unclassified)
+            f(a)
         } else {
-            Err(AError::Unclassified)
+            Err(AError::NotLoaded)
         }
     }
 
-    fn mk_doc_url(def: &Def, analysis: &Analysis) -> Option<String> {
+    fn mk_doc_url(def: &Def, analysis: &Analysis) -> AResult<String> {
         if !def.distro_crate {
-            return None;
+            return Err(AError::NotDistroCrate);
         }
 
         if def.parent.is_none() && def.qualname.contains('<') {
@@ -511,7 +991,7 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
                 "mk_doc_url, bailing, found generic qualname: `{}`",
                 def.qualname
             );
-            return None;
+            return Err(AError::NoUrl);
         }
 
         match def.parent {
@@ -521,7 +1001,7 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
                     DefKind::TupleVariant | DefKind::StructVariant => {
                         let ns = name_space_for_def_kind(def.kind);
                         let mut res = AnalysisHost::<L>::mk_doc_url(parent, analysis)
-                            .unwrap_or_else(|| "".into());
+                            .unwrap_or_else(|_| "".into());
                         res.push_str(&format!("#{}.{}", def.name, ns));
                         res
                     }
@@ -550,7 +1030,7 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
             None => {
                 let qualpath = def.qualname.replace("::", "/");
                 let ns = name_space_for_def_kind(def.kind);
-                Some(format!(
+                Ok(format!(
                     "{}/{}.{}.html",
                     analysis.doc_url_base,
                     qualpath,
@@ -560,15 +1040,17 @@ impl<L: AnalysisLoader> AnalysisHost<L> {
         }
     }
 
-    fn mk_src_url(def: &Def, path_prefix: Option<&PathBuf>, analysis: &Analysis) -> Option<String> {
+    fn mk_src_url(def: &Def, path_prefix: Option<&PathBuf>, analysis: &Analysis) -> AResult<String> {
         if !def.distro_crate {
-            return None;
+            return Err(AError::NotDistroCrate);
         }
 
         let file_path = &def.span.file;
-        let file_path = file_path.strip_prefix(path_prefix?).ok()?;
+        let file_path = path_prefix
+            .and_then(|prefix| file_path.strip_prefix(prefix).ok())
+            .ok_or(AError::NoUrl)?;
 
-        Some(format!(
+        Ok(format!(
             "{}/{}#L{}-L{}",
             analysis.src_url_base,
             file_path.to_str().unwrap(),
@@ -588,7 +1070,19 @@ impl ::std::error::Error for AError {
     fn description(&self) -> &str {
         match *self {
             AError::MutexPoison => "poison error in a mutex (usually a secondary error)",
-            AError::Unclassified => "unknown error",
+            AError::NotLoaded => "no analysis data has been loaded",
+            AError::NoDefAtSpan(_) => "no definition or reference at span",
+            AError::UnknownId(_) => "no definition for id in the loaded crates",
+            AError::NoDefsInFile(_) => "no definitions recorded for file",
+            AError::NoDocs => "definition has no documentation",
+            AError::NoSignature => "definition has no signature",
+            AError::NotDistroCrate => "definition is not in a Rust distribution crate",
+            AError::NoUrl => "could not construct a url for the definition",
+            AError::Io(_) => "io error",
+            AError::InvalidQuery(_) => "invalid symbol query pattern",
+            AError::InvalidCache => "invalid or out of date analysis cache",
+            AError::RenameRefused(_) => "the definition cannot be renamed safely",
+            AError::UnknownGlobModule(_) => "the module imported by the glob is not known",
         }
     }
 }
@@ -599,6 +1093,12 @@ impl ::std::fmt::Display for AError {
     }
 }
 
+impl From<io::Error> for AError {
+    fn from(err: io::Error) -> AError {
+        AError::Io(err.kind())
+    }
+}
+
 impl<T> From<::std::sync::PoisonError<T>> for AError {
     fn from(_: ::std::sync::PoisonError<T>) -> AError {
         AError::MutexPoison
@@ -9,26 +9,39 @@
 //! For processing the raw save-analysis data from rustc into the rls
 //! in-memory representation.
 
-use analysis::{Def, Glob, PerCrateAnalysis, Ref};
+use analysis::{Analysis, Attribute, Call, Def, Glob, Impl, Import, PerCrateAnalysis, Ref,
+               RefKind, Relation, RelationKind, SigElement, Signature};
 use data;
-use raw::{self, RelationKind, CrateId, DefKind};
+use raw::{self, CrateId, DefKind};
 use {AResult, AnalysisHost, Id, Span, NULL};
 use loader::AnalysisLoader;
 use util;
 
 use span;
 
+use std::cmp::Ordering;
 use std::collections::{HashSet, HashMap};
-use std::collections::hash_map::Entry;
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::hash::{Hash, Hasher};
 use std::iter::Extend;
+use std::mem;
 use std::path::{Path, PathBuf};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::u32;
 
 use fst;
 use itertools::Itertools;
+use rayon::prelude::*;
 
 // f is a function used to record the lowered crate into analysis.
+//
+// Crates are lowered in parallel and independently of the host, in two passes:
+// first we read every crate's defs, then its imports, refs and impls (which
+// may refer to defs in any of the crates). Finally, the lowered crates are
+// recorded in their original order.
+//
+// When a crate is reloaded, only the files whose raw data has changed are
+// lowered again, see `LoweringCrate::reuse_unchanged_files`.
 pub fn lower<F, L>(
     raw_analysis: Vec<raw::Crate>,
     base_dir: &Path,
@@ -42,27 +55,81 @@ where
     let rss = util::get_resident().unwrap_or(0);
     let t_start = Instant::now();
 
-    // Keep a queue of crates that we are yet to overwrite as part of the lowering
-    // process (to know which already-existing defs we can overwrite and lower)
-    let mut invalidated_crates: Vec<_> = raw_analysis.iter().map(|c| c.id.clone()).collect();
+    let crate_ids: Vec<_> = raw_analysis.iter().map(|c| c.id.clone()).collect();
+
+    // Assign global crate numbers to all crates (and their dependencies) up
+    // front, so that ids are stable whichever order the crates are lowered in.
+    let mut crates: Vec<_> = {
+        let mut master_crate_map = analysis.master_crate_map.lock()?;
+        raw_analysis
+            .into_iter()
+            .enumerate()
+            .map(|(index, mut krate)| {
+                let reader = CrateReader::from_prelude(
+                    krate.analysis.prelude.take().unwrap(),
+                    &mut master_crate_map,
+                    base_dir,
+                    krate.path_rewrite.take(),
+                );
+                let per_crate = PerCrateAnalysis::new(krate.timestamp, krate.path.take());
+                LoweringCrate {
+                    index,
+                    krate,
+                    reader,
+                    per_crate,
+                    digests: HashMap::new(),
+                    time: Duration::from_secs(0),
+                }
+            })
+            .collect()
+    };
 
-    for c in raw_analysis {
-        let t_start = Instant::now();
+    crates.par_iter_mut().for_each(|c| c.digest_files());
+
+    let mut ctx = {
+        let analysis = analysis.analysis.lock()?;
+        LoweringContext::new(analysis.as_ref().unwrap(), &mut crates, &crate_ids)
+    };
+
+    // Crates with the same name are lowered in order on the same thread, so
+    // that we can skip any defs which are duplicated between them.
+    let mut groups: Vec<Vec<LoweringCrate>> = vec![];
+    for c in crates {
+        if let Some(group) = groups.iter_mut().find(|g| g[0].reader.crate_name == c.reader.crate_name) {
+            group.push(c);
+            continue;
+        }
+        groups.push(vec![c]);
+    }
 
-        let (per_crate, id) = CrateReader::read_crate(analysis, c, base_dir, &invalidated_crates);
-        invalidated_crates.retain(|elem| *elem != id);
+    let groups: Vec<_> = groups
+        .into_par_iter()
+        .map(|group| read_group_defs(group, &ctx))
+        .collect();
 
-        let time = t_start.elapsed();
+    for c in groups.iter().flat_map(|g| g.iter()) {
+        ctx.add_defs(&c.per_crate.defs);
+    }
+
+    let groups: Vec<_> = groups
+        .into_par_iter()
+        .map(|group| read_group_refs(group, &ctx))
+        .collect();
+
+    let mut crates: Vec<_> = groups.into_iter().flat_map(|g| g.into_iter()).collect();
+    crates.sort_by_key(|c| c.index);
+    for c in crates {
+        let id = c.krate.id;
         info!(
             "Lowering {} in {:.2}s",
             format!("{} ({:?})", id.name, id.disambiguator),
-            time.as_secs() as f64 + time.subsec_nanos() as f64 / 1_000_000_000.0
+            c.time.as_secs() as f64 + c.time.subsec_nanos() as f64 / 1_000_000_000.0
         );
-        info!("    defs:  {}", per_crate.defs.len());
-        info!("    refs:  {}", per_crate.ref_spans.len());
-        info!("    globs: {}", per_crate.globs.len());
+        info!("    defs:  {}", c.per_crate.defs.len());
+        info!("    refs:  {}", c.per_crate.ref_spans.len());
+        info!("    globs: {}", c.per_crate.globs.len());
 
-        f(analysis, per_crate, id)?;
+        f(analysis, c.per_crate, id)?;
     }
 
     let time = t_start.elapsed();
@@ -76,11 +143,190 @@ where
     Ok(())
 }
 
+// Reads the defs of a group of crates with the same name, in order.
+fn read_group_defs(mut group: Vec<LoweringCrate>, ctx: &LoweringContext) -> Vec<LoweringCrate> {
+    for i in 0..group.len() {
+        let (lowered, rest) = group.split_at_mut(i);
+        let c = &mut rest[0];
+        let t_start = Instant::now();
+
+        let defs = mem::replace(&mut c.krate.analysis.defs, vec![]);
+        let is_distro_crate = c.krate.analysis.config.distro_crate;
+        c.reader.read_defs(defs, &mut c.per_crate, is_distro_crate, ctx, lowered);
+
+        c.time += t_start.elapsed();
+    }
+    group
+}
+
+// Reads the imports, refs and impls of a group of crates with the same name,
+// in order. Must be called after the defs of all crates have been read.
+fn read_group_refs(mut group: Vec<LoweringCrate>, ctx: &LoweringContext) -> Vec<LoweringCrate> {
+    for i in 0..group.len() {
+        let (lowered, rest) = group.split_at_mut(i);
+        let c = &mut rest[0];
+        let t_start = Instant::now();
+
+        let analysis = mem::replace(&mut c.krate.analysis, data::Analysis::new(Default::default()));
+        c.reader.read_imports(analysis.imports, &mut c.per_crate, ctx, lowered);
+        c.reader.read_refs(analysis.refs, &mut c.per_crate, ctx);
+        c.reader.read_macro_refs(analysis.macro_refs, &mut c.per_crate, ctx);
+        c.reader.read_impls(analysis.relations, analysis.impls, &mut c.per_crate, ctx);
+        c.per_crate.index_spans();
+
+        c.time += t_start.elapsed();
+    }
+    group
+}
+
+/// A crate which is in the process of being lowered.
+struct LoweringCrate {
+    /// The position of the crate in the data passed to `lower`.
+    index: usize,
+    krate: raw::Crate,
+    reader: CrateReader,
+    per_crate: PerCrateAnalysis,
+    /// The digest of each file's raw data, by raw file name, see
+    /// `CrateReader::file_digests`.
+    digests: HashMap<PathBuf, u64>,
+    time: Duration,
+}
+
+impl LoweringCrate {
+    fn digest_files(&mut self) {
+        let t_start = Instant::now();
+
+        self.digests = self.reader.file_digests(&self.krate.analysis);
+        let reader = &self.reader;
+        self.per_crate.file_digests = self.digests
+            .iter()
+            .map(|(file_name, digest)| {
+                (lower_path(file_name, &reader.base_dir, &reader.path_rewrite), *digest)
+            })
+            .collect();
+
+        self.time += t_start.elapsed();
+    }
+
+    // Finds the files whose raw data is the same as when `old`, the data
+    // this crate replaces, was lowered, and takes their entries in the
+    // per-file indexes from `old` rather than lowering them again.
+    fn reuse_unchanged_files(&mut self, old: &PerCrateAnalysis) {
+        let mut files = HashSet::new();
+        for (file_name, digest) in &self.digests {
+            let file = lower_path(file_name, &self.reader.base_dir, &self.reader.path_rewrite);
+            if old.file_digests.get(&file) == Some(digest) {
+                self.reader.unchanged_files.insert(file_name.clone());
+                files.insert(file);
+            }
+        }
+        trace!("reusing the lowered data for {:?}", files);
+        self.per_crate.reuse_files(old, &files);
+    }
+}
+
+/// The data from the host and the other crates being lowered which is needed
+/// when lowering a crate. This is gathered up front so that crates can be
+/// lowered in parallel without locking the host.
+struct LoweringContext {
+    /// All defs which a ref may refer to, i.e., the defs already in the host
+    /// and, once they have been read, the defs of the crates being lowered.
+    known_defs: HashSet<Id>,
+    /// The spans of macro defs per file, see `LoweringContext::macro_def_for_span`.
+    macro_defs: HashMap<PathBuf, Vec<(Span, Id)>>,
+    /// Spans of defs and globs in crates which are in the host and share a
+    /// name with a crate being lowered, see `CrateReader::has_congruent_def`.
+    homonym_defs: HashMap<Id, Span>,
+    homonym_globs: HashMap<u32, HashSet<Span>>,
+}
+
+impl LoweringContext {
+    fn new(analysis: &Analysis, crates: &mut [LoweringCrate], crate_ids: &[CrateId]) -> LoweringContext {
+        let mut ctx = LoweringContext {
+            known_defs: HashSet::new(),
+            macro_defs: HashMap::new(),
+            homonym_defs: HashMap::new(),
+            homonym_globs: HashMap::new(),
+        };
+        for c in analysis.per_crate.values() {
+            ctx.add_defs(&c.defs);
+        }
+
+        for c in crates {
+            // Don't take into account crates that we are about to replace as part
+            // of the lowering. This often happens when we reload definitions for
+            // the same crate. Naturally most of the definitions will stay the same
+            // for incremental changes but will be overwritten - don't ignore them!
+            let homonyms: Vec<_> = analysis.per_crate
+                .iter()
+                .filter(|&(id, _)| id.name == c.reader.crate_name && !crate_ids.contains(id))
+                .map(|(_, homonym)| homonym)
+                .collect();
+            for homonym in homonyms {
+                c.reader.crate_homonyms.push(homonym.global_crate_num);
+                if ctx.homonym_globs.contains_key(&homonym.global_crate_num) {
+                    continue;
+                }
+                ctx.homonym_defs.extend(homonym.defs.iter().map(|(id, def)| (*id, def.span.clone())));
+                ctx.homonym_globs.insert(
+                    homonym.global_crate_num,
+                    homonym.globs.keys().cloned().collect(),
+                );
+            }
+
+            // Which of several crates with the same name a def or glob is
+            // recorded in depends on all of them, so we only reuse the data of
+            // a crate without any.
+            let homonyms = crate_ids.iter().filter(|id| id.name == c.reader.crate_name).count();
+            if let Some(old) = analysis.per_crate.get(&c.krate.id) {
+                if homonyms == 1 && c.reader.crate_homonyms.is_empty() {
+                    c.reuse_unchanged_files(old);
+                }
+            }
+        }
+
+        ctx
+    }
+
+    fn add_defs(&mut self, defs: &HashMap<Id, Def>) {
+        self.known_defs.extend(defs.keys().cloned());
+        for (id, def) in defs {
+            if def.kind == DefKind::Macro {
+                self.macro_defs
+                    .entry(def.span.file.clone())
+                    .or_insert_with(|| vec![])
+                    .push((def.span.clone(), *id));
+            }
+        }
+    }
+
+    // Macro refs give the span of the macro's definition, rather than its id,
+    // so we look for a macro def within that span.
+    fn macro_def_for_span(&self, callee_span: &Span) -> Option<Id> {
+        self.macro_defs.get(&callee_span.file)?
+            .iter()
+            .find(|&&(ref span, _)| {
+                callee_span.range.start() <= span.range.start()
+                    && span.range.end() <= callee_span.range.end()
+            })
+            .map(|&(_, id)| id)
+    }
+}
+
 fn lower_span(raw_span: &raw::SpanData, base_dir: &Path, path_rewrite: &Option<PathBuf>) -> Span {
-    let file_name = &raw_span.file_name;
+    // Rustc uses 1-indexed rows and columns, the RLS uses 0-indexed.
+    span::Span::new(
+        raw_span.line_start.zero_indexed(),
+        raw_span.line_end.zero_indexed(),
+        raw_span.column_start.zero_indexed(),
+        raw_span.column_end.zero_indexed(),
+        lower_path(&raw_span.file_name, base_dir, path_rewrite),
+    )
+}
 
-    // Go from relative to absolute paths.
-    let file_name = if let &Some(ref prefix) = path_rewrite {
+// Go from relative to absolute paths.
+fn lower_path(file_name: &Path, base_dir: &Path, path_rewrite: &Option<PathBuf>) -> PathBuf {
+    if let &Some(ref prefix) = path_rewrite {
         // Invariant: !file_name.is_absolute()
         // We don't assert this because better to have an incorrect span than to
         // panic.
@@ -90,44 +336,45 @@ fn lower_span(raw_span: &raw::SpanData, base_dir: &Path, path_rewrite: &Option<P
         file_name.to_owned()
     } else {
         base_dir.join(file_name)
-    };
+    }
+}
 
-    // Rustc uses 1-indexed rows and columns, the RLS uses 0-indexed.
-    span::Span::new(
-        raw_span.line_start.zero_indexed(),
-        raw_span.line_end.zero_indexed(),
-        raw_span.column_start.zero_indexed(),
-        raw_span.column_end.zero_indexed(),
-        file_name,
-    )
+fn hash_span<H: Hasher>(span: &raw::SpanData, state: &mut H) {
+    span.byte_start.hash(state);
+    span.byte_end.hash(state);
+    span.line_start.0.hash(state);
+    span.line_end.0.hash(state);
+    span.column_start.0.hash(state);
+    span.column_end.0.hash(state);
 }
 
 /// Responsible for processing the raw `data::Analysis`, including translating
 /// from local crate ids to global crate ids, and creating lowered
 /// `PerCrateAnalysis`.
-struct CrateReader<'a> {
+struct CrateReader {
     /// This is effectively a map from local crate id -> global crate id, where
     /// local crate id are indices 0...external_crate_count.
     crate_map: Vec<u32>,
     base_dir: PathBuf,
     crate_name: String,
     path_rewrite: Option<PathBuf>,
-    crate_homonyms: Vec<CrateId>,
-    /// List of crates that are invalidated (replaced) as part of the current
-    /// lowering process. These will be overriden and their definitions should
-    /// not be taken into account when checking if we need to ignore duplicated
-    /// item.
-    invalidated_crates: &'a [CrateId],
+    /// Global crate numbers of crates in the host with the same name as this
+    /// one, which are not being replaced as part of the current lowering
+    /// process. See `LoweringContext::new`.
+    crate_homonyms: Vec<u32>,
+    /// The raw names of files whose entries in the per-file indexes are taken
+    /// from the data this crate replaces, see
+    /// `LoweringCrate::reuse_unchanged_files`.
+    unchanged_files: HashSet<PathBuf>,
 }
 
-impl<'a> CrateReader<'a> {
+impl CrateReader {
     fn from_prelude(
         mut prelude: raw::CratePreludeData,
         master_crate_map: &mut HashMap<CrateId, u32>,
         base_dir: &Path,
         path_rewrite: Option<PathBuf>,
-        invalidated_crates: &'a [CrateId],
-    ) -> CrateReader<'a> {
+    ) -> CrateReader {
         fn fetch_crate_index(map: &mut HashMap<CrateId, u32>,
                              id: CrateId) -> u32 {
             let next = map.len() as u32;
@@ -156,93 +403,149 @@ impl<'a> CrateReader<'a> {
         CrateReader {
             crate_map,
             base_dir: base_dir.to_owned(),
-            crate_homonyms: master_crate_map.keys().filter(|cid| cid.name == crate_id.name).cloned().collect(),
+            crate_homonyms: vec![],
             crate_name: crate_id.name,
             path_rewrite,
-            invalidated_crates,
+            unchanged_files: HashSet::new(),
         }
     }
 
-    /// Lowers a given `raw::Crate` into `AnalysisHost`.
-    fn read_crate<L: AnalysisLoader>(
-        project_analysis: &AnalysisHost<L>,
-        krate: raw::Crate,
-        base_dir: &Path,
-        invalidated_crates: &[CrateId],
-    ) -> (PerCrateAnalysis, CrateId) {
-        let reader = CrateReader::from_prelude(
-            krate.analysis.prelude.unwrap(),
-            &mut project_analysis.master_crate_map.lock().unwrap(),
-            base_dir,
-            krate.path_rewrite,
-            invalidated_crates,
-        );
+    // A digest of the raw data which each file's entries in the per-file
+    // indexes (`defs_per_file`, `def_id_for_span`, `ref_spans`, `calls`,
+    // `globs` and the imports) are lowered from, by raw file name. As well as
+    // the file's defs, refs and imports, these depend on how ids and paths are
+    // lowered, and on the crate's macros, which macro refs are resolved to.
+    //
+    // Ids are numbered through the whole crate, so adding or removing a def
+    // changes the digest of every file which has or refers to a later def.
+    fn file_digests(&self, analysis: &data::Analysis) -> HashMap<PathBuf, u64> {
+        let mut crate_hasher = DefaultHasher::new();
+        self.crate_map.hash(&mut crate_hasher);
+        self.base_dir.hash(&mut crate_hasher);
+        self.path_rewrite.hash(&mut crate_hasher);
+        for d in analysis.defs.iter().filter(|d| d.kind == DefKind::Macro) {
+            d.id.hash(&mut crate_hasher);
+            hash_span(&d.span, &mut crate_hasher);
+        }
+        let crate_digest = crate_hasher.finish();
+
+        let mut hashers: HashMap<&Path, DefaultHasher> = HashMap::new();
+        fn hasher<'a, 'b>(
+            hashers: &'b mut HashMap<&'a Path, DefaultHasher>,
+            span: &'a raw::SpanData,
+            crate_digest: u64,
+        ) -> &'b mut DefaultHasher {
+            let hasher = hashers.entry(&span.file_name).or_insert_with(|| {
+                let mut hasher = DefaultHasher::new();
+                crate_digest.hash(&mut hasher);
+                hasher
+            });
+            hash_span(span, hasher);
+            hasher
+        }
 
-        let mut per_crate = PerCrateAnalysis::new(krate.timestamp, krate.path);
-
-        let is_distro_crate = krate.analysis.config.distro_crate;
-        reader.read_defs(krate.analysis.defs, &mut per_crate, is_distro_crate, project_analysis);
-        reader.read_imports(krate.analysis.imports, &mut per_crate, project_analysis);
-        reader.read_refs(krate.analysis.refs, &mut per_crate, project_analysis);
-        reader.read_impls(krate.analysis.relations, &mut per_crate, project_analysis);
-        per_crate.global_crate_num = reader.crate_map[0];
-
-        {
-            let analysis = &mut project_analysis.analysis.lock().unwrap();
-            analysis.as_mut()
-                .unwrap()
-                .crate_names
-                .entry(krate.id.name.clone())
-                .or_insert_with(|| vec![])
-                .push(krate.id.clone());
+        for d in &analysis.defs {
+            let hasher = hasher(&mut hashers, &d.span, crate_digest);
+            (d.id, d.kind as u32, &d.qualname, d.decl_id).hash(hasher);
+        }
+        for i in &analysis.imports {
+            let hasher = hasher(&mut hashers, &i.span, crate_digest);
+            (i.kind as u32, i.ref_id, &i.name, &i.value, i.parent).hash(hasher);
+            match i.alias_span {
+                Some(ref alias) => hash_span(alias, hasher),
+                None => 0.hash(hasher),
+            }
+        }
+        for r in &analysis.refs {
+            let hasher = hasher(&mut hashers, &r.span, crate_digest);
+            (r.kind as u32, r.ref_id).hash(hasher);
+        }
+        for r in &analysis.macro_refs {
+            let hasher = hasher(&mut hashers, &r.span, crate_digest);
+            hash_span(&r.callee_span, hasher);
         }
 
-        (per_crate, krate.id)
+        hashers
+            .into_iter()
+            .map(|(file_name, hasher)| (file_name.to_owned(), hasher.finish()))
+            .collect()
     }
 
-    fn read_imports<L: AnalysisLoader>(
+    fn read_imports(
         &self,
         imports: Vec<raw::Import>,
         analysis: &mut PerCrateAnalysis,
-        project_analysis: &AnalysisHost<L>,
+        ctx: &LoweringContext,
+        lowered_homonyms: &[LoweringCrate],
     ) {
         for i in imports {
+            if self.unchanged_files.contains(&i.span.file_name) {
+                continue;
+            }
             let span = lower_span(&i.span, &self.base_dir, &self.path_rewrite);
+            let alias = i.alias_span
+                .as_ref()
+                .map(|s| lower_span(s, &self.base_dir, &self.path_rewrite));
+            let target = i.ref_id
+                .as_ref()
+                .map(|id| self.id_from_compiler_id(id))
+                .filter(|id| *id != NULL);
             if !i.value.is_empty() {
                 // A glob import.
-                if !self.has_congruent_glob(&span, project_analysis) {
-                    let glob = Glob { value: i.value };
+                if !self.has_congruent_glob(&span, ctx, lowered_homonyms) {
+                    let glob = Glob { value: i.value.clone() };
                     trace!("record glob {:?} {:?}", span, glob);
-                    analysis.globs.insert(span, glob);
+                    analysis.globs.insert(span.clone(), glob);
                 }
-            } else if let Some(ref ref_id) = i.ref_id {
+            } else if let Some(def_id) = target {
                 // Import where we know the referred def.
-                let def_id = self.id_from_compiler_id(ref_id);
-                self.record_ref(def_id, span, analysis, project_analysis);
-                if let Some(alias_span) = i.alias_span {
-                    let alias_span = lower_span(&alias_span, &self.base_dir, &self.path_rewrite);
-                    self.record_ref(def_id, alias_span, analysis, project_analysis);
-                    let mut analysis = project_analysis.analysis.lock().unwrap();
-                    analysis.as_mut().unwrap().aliased_imports.insert(def_id);
+                self.record_ref(def_id, span.clone(), RefKind::Import, analysis, ctx);
+                if let Some(ref alias) = alias {
+                    self.record_ref(def_id, alias.clone(), RefKind::Import, analysis, ctx);
                 }
             }
+
+            let import = Import {
+                kind: i.kind,
+                span,
+                alias,
+                name: i.name,
+                value: i.value,
+                target,
+                parent: i.parent.map(|id| self.id_from_compiler_id(&id)),
+            };
+            trace!("record import {:?}", import);
+            if let Some(def_id) = target {
+                analysis.importers.entry(def_id).or_insert_with(Vec::new).push(import.clone());
+            }
+            analysis
+                .imports_per_file
+                .entry(import.span.file.clone())
+                .or_insert_with(Vec::new)
+                .push(import);
+        }
+        for imports in analysis.imports_per_file.values_mut() {
+            imports.sort_by_key(|i| i.span.range.start());
+        }
+        for imports in analysis.importers.values_mut() {
+            imports.sort_by(|a, b| {
+                (&a.span.file, a.span.range.start()).cmp(&(&b.span.file, b.span.range.start()))
+            });
         }
     }
 
-    fn record_ref<L: AnalysisLoader>(
+    fn record_ref(
         &self,
         def_id: Id,
         span: Span,
+        kind: RefKind,
         analysis: &mut PerCrateAnalysis,
-        project_analysis: &AnalysisHost<L>,
-    ) {
-        if def_id != NULL && (project_analysis.has_def(def_id) || analysis.defs.contains_key(&def_id)) {
+        ctx: &LoweringContext,
+    ) -> bool {
+        if def_id != NULL && (ctx.known_defs.contains(&def_id) || analysis.defs.contains_key(&def_id)) {
             trace!("record_ref {:?} {}", span, def_id);
             match analysis.def_id_for_span.entry(span.clone()) {
-                Entry::Occupied(mut oe) => {
-                    let new = oe.get().add_id(def_id);
-                    oe.insert(new);
-                }
+                Entry::Occupied(mut oe) => oe.get_mut().add_id(def_id),
                 Entry::Vacant(ve) => {
                     ve.insert(Ref::Id(def_id));
                 }
@@ -251,7 +554,10 @@ impl<'a> CrateReader<'a> {
                 .ref_spans
                 .entry(def_id)
                 .or_insert_with(|| vec![])
-                .push(span);
+                .push((span, kind));
+            true
+        } else {
+            false
         }
     }
 
@@ -261,86 +567,87 @@ impl<'a> CrateReader<'a> {
     // want to ensure that we only record defs once, even if the defintion is in multiple crates.
     // So we compare the crate-local id and span and skip any subsequent defs which match already
     // present defs.
-    fn has_congruent_def<L: AnalysisLoader>(&self, local_id: u32, span: &Span, project_analysis: &AnalysisHost<L>) -> bool {
-        self.has_congruent_item(project_analysis, |per_crate| per_crate.has_congruent_def(local_id, span))
-    }
-
-    fn has_congruent_glob<L: AnalysisLoader>(&self, span: &Span, project_analysis: &AnalysisHost<L>) -> bool {
-        self.has_congruent_item(project_analysis, |per_crate| per_crate.globs.contains_key(span))
+    fn has_congruent_def(
+        &self,
+        local_id: u32,
+        span: &Span,
+        ctx: &LoweringContext,
+        lowered_homonyms: &[LoweringCrate],
+    ) -> bool {
+        self.crate_homonyms.iter().any(|&krate| {
+            let id = Id::from_crate_and_local(krate, local_id);
+            ctx.homonym_defs.get(&id).map_or(false, |existing| existing == span)
+        }) || lowered_homonyms.iter().any(|c| c.per_crate.has_congruent_def(local_id, span))
     }
 
-    fn has_congruent_item<L, P>(&self, project_analysis: &AnalysisHost<L>, pred: P) -> bool
-        where
-            L: AnalysisLoader,
-            P: Fn(&PerCrateAnalysis) -> bool,
-    {
-        if self.crate_homonyms.is_empty() {
-            return false;
-        }
-
-        let project_analysis = project_analysis.analysis.lock().unwrap();
-        let project_analysis = project_analysis.as_ref().unwrap();
-
-        // Don't take into account crates that we are about to replace as part
-        // of the lowering. This often happens when we reload definitions for
-        // the same crate. Naturally most of the definitions will stay the same
-        // for incremental changes but will be overwritten - don't ignore them!
-        let homonyms_to_consider = self
-            .crate_homonyms
-            .iter()
-            .filter(|c| !self.invalidated_crates.contains(c));
-
-        homonyms_to_consider
-            .filter_map(|ch| project_analysis.per_crate.get(ch))
-            .any(pred)
+    fn has_congruent_glob(
+        &self,
+        span: &Span,
+        ctx: &LoweringContext,
+        lowered_homonyms: &[LoweringCrate],
+    ) -> bool {
+        self.crate_homonyms.iter().any(|krate| {
+            ctx.homonym_globs.get(krate).map_or(false, |globs| globs.contains(span))
+        }) || lowered_homonyms.iter().any(|c| c.per_crate.globs.contains_key(span))
     }
 
-    fn read_defs<L: AnalysisLoader>(
+    fn read_defs(
         &self,
         defs: Vec<raw::Def>,
         analysis: &mut PerCrateAnalysis,
         distro_crate: bool,
-        project_analysis: &AnalysisHost<L>,
+        ctx: &LoweringContext,
+        lowered_homonyms: &[LoweringCrate],
     ) {
+        analysis.global_crate_num = self.crate_map[0];
+
         let mut defs_to_index = Vec::new();
         for d in defs {
             if bad_span(&d.span, d.kind == DefKind::Mod) {
                 continue;
             }
             let span = lower_span(&d.span, &self.base_dir, &self.path_rewrite);
-            if self.has_congruent_def(d.id.index, &span, project_analysis) {
+            if self.has_congruent_def(d.id.index, &span, ctx, lowered_homonyms) {
                 trace!("read_defs: has_congruent_def({}, {:?}), skipping", d.id.index, span);
                 continue;
             }
 
             let id = self.id_from_compiler_id(&d.id);
             if id != NULL && !analysis.defs.contains_key(&id) {
-                let file_name = span.file.clone();
-                analysis
-                    .defs_per_file
-                    .entry(file_name)
-                    .or_insert_with(|| vec![])
-                    .push(id);
-                let decl_id = match d.decl_id {
-                    Some(ref decl_id) => {
-                        let def_id = self.id_from_compiler_id(decl_id);
-                        analysis
-                            .ref_spans
-                            .entry(def_id)
-                            .or_insert_with(|| vec![])
-                            .push(span.clone());
-                        Ref::Id(def_id)
-                    }
-                    None => Ref::Id(id),
-                };
-                match analysis.def_id_for_span.entry(span.clone()) {
-                    Entry::Occupied(_) => {
-                        debug!("def already exists at span: {:?} {:?}", span, d);
-                    }
-                    Entry::Vacant(ve) => {
-                        ve.insert(decl_id);
+                // The def itself is always lowered, but the file's entries in
+                // the per-file indexes may have been reused.
+                if !self.unchanged_files.contains(&d.span.file_name) {
+                    let file_name = span.file.clone();
+                    analysis
+                        .defs_per_file
+                        .entry(file_name)
+                        .or_insert_with(|| vec![])
+                        .push(id);
+                    let decl_id = match d.decl_id {
+                        Some(ref decl_id) => {
+                            let def_id = self.id_from_compiler_id(decl_id);
+                            analysis
+                                .ref_spans
+                                .entry(def_id)
+                                .or_insert_with(|| vec![])
+                                .push((span.clone(), RefKind::Impl));
+                            Ref::Id(def_id)
+                        }
+                        None => Ref::Id(id),
+                    };
+                    match analysis.def_id_for_span.entry(span.clone()) {
+                        Entry::Occupied(_) => {
+                            debug!("def already exists at span: {:?} {:?}", span, d);
+                        }
+                        Entry::Vacant(ve) => {
+                            ve.insert(decl_id);
+                        }
                     }
                 }
+                match analysis.defs_per_kind.iter().position(|&(kind, _)| kind == d.kind) {
+                    Some(i) => analysis.defs_per_kind[i].1.push(id),
+                    None => analysis.defs_per_kind.push((d.kind, vec![id])),
+                }
 
                 analysis
                     .def_names
@@ -377,7 +684,11 @@ impl<'a> CrateReader<'a> {
                     distro_crate,
                     parent: parent,
                     docs: d.docs,
-                    // sig: d.sig.map(|ref s| self.lower_sig(s, &self.base_dir)),
+                    sig: d.sig.map(|ref s| self.lower_sig(s)),
+                    attributes: d.attributes.into_iter().map(|a| Attribute {
+                        value: a.value,
+                        span: lower_span(&a.span, &self.base_dir, &self.path_rewrite),
+                    }).collect(),
                 };
                 trace!(
                     "record def: {:?}/{:?} ({}): {:?}",
@@ -412,38 +723,100 @@ impl<'a> CrateReader<'a> {
         }
     }
 
-    fn read_refs<L: AnalysisLoader>(
+    fn read_refs(
         &self,
         refs: Vec<raw::Ref>,
         analysis: &mut PerCrateAnalysis,
-        project_analysis: &AnalysisHost<L>,
+        ctx: &LoweringContext,
     ) {
+        let items = items_per_file(analysis);
         for r in refs {
-            if r.span.file_name.to_str().map(|s| s.ends_with('>')).unwrap_or(true) {
+            if r.span.file_name.to_str().map(|s| s.ends_with('>')).unwrap_or(true)
+                || self.unchanged_files.contains(&r.span.file_name)
+            {
                 continue;
             }
             let def_id = self.id_from_compiler_id(&r.ref_id);
             let span = lower_span(&r.span, &self.base_dir, &self.path_rewrite);
-            self.record_ref(def_id, span, analysis, project_analysis);
+            let kind = match r.kind {
+                raw::RefKind::Function => RefKind::Function,
+                raw::RefKind::Mod => RefKind::Mod,
+                raw::RefKind::Type => RefKind::Type,
+                raw::RefKind::Variable => RefKind::Variable,
+            };
+            let call = match r.kind {
+                raw::RefKind::Function => enclosing_function(&items, &span).map(|caller| Call {
+                    caller,
+                    callee: def_id,
+                    span: span.clone(),
+                }),
+                _ => None,
+            };
+            if self.record_ref(def_id, span, kind, analysis, ctx) {
+                if let Some(call) = call {
+                    trace!("record call {:?} {} -> {}", call.span, call.caller, call.callee);
+                    if call.caller != call.callee {
+                        analysis
+                            .calls
+                            .entry(call.callee)
+                            .or_insert_with(|| vec![])
+                            .push(call.clone());
+                    }
+                    analysis
+                        .calls
+                        .entry(call.caller)
+                        .or_insert_with(|| vec![])
+                        .push(call);
+                }
+            }
         }
     }
 
-    fn read_impls<L: AnalysisLoader>(
+    fn read_macro_refs(
+        &self,
+        macro_refs: Vec<raw::MacroRef>,
+        analysis: &mut PerCrateAnalysis,
+        ctx: &LoweringContext,
+    ) {
+        for r in macro_refs {
+            if r.span.file_name.to_str().map(|s| s.ends_with('>')).unwrap_or(true)
+                || self.unchanged_files.contains(&r.span.file_name)
+            {
+                continue;
+            }
+            let callee_span = lower_span(&r.callee_span, &self.base_dir, &self.path_rewrite);
+            match ctx.macro_def_for_span(&callee_span) {
+                Some(def_id) => {
+                    let span = lower_span(&r.span, &self.base_dir, &self.path_rewrite);
+                    self.record_ref(def_id, span, RefKind::Macro, analysis, ctx);
+                }
+                None => trace!("no def for macro {} at {:?}", r.qualname, callee_span),
+            }
+        }
+    }
+
+    fn read_impls(
         &self,
         relations: Vec<raw::Relation>,
+        impls: Vec<raw::Impl>,
         analysis: &mut PerCrateAnalysis,
-        project_analysis: &AnalysisHost<L>,
+        ctx: &LoweringContext,
     ) {
+        // The self type and trait of each impl, by the impl's id.
+        let mut impl_types = HashMap::new();
         for r in relations {
-            match r.kind {
-                RelationKind::Impl { .. } => {}
-                _ => continue,
-            }
-            let self_id = self.id_from_compiler_id(&r.from);
-            let trait_id = self.id_from_compiler_id(&r.to);
+            let (kind, impl_id) = match r.kind {
+                raw::RelationKind::Impl { id } => (RelationKind::Impl, Some(id)),
+                raw::RelationKind::SuperTrait => (RelationKind::SuperTrait, None),
+            };
+            let from_id = self.id_from_compiler_id(&r.from);
+            let to_id = self.id_from_compiler_id(&r.to);
             let span = lower_span(&r.span, &self.base_dir, &self.path_rewrite);
-            if self_id != NULL {
-                if let Some(self_id) = abs_ref_id(self_id, analysis, project_analysis) {
+            let from_id = if from_id != NULL { abs_ref_id(from_id, analysis, ctx) } else { None };
+            let to_id = if to_id != NULL { abs_ref_id(to_id, analysis, ctx) } else { None };
+
+            if kind == RelationKind::Impl {
+                if let Some(self_id) = from_id {
                     trace!("record impl for self type {:?} {}", span, self_id);
                     analysis
                         .impls
@@ -451,38 +824,71 @@ impl<'a> CrateReader<'a> {
                         .or_insert_with(|| vec![])
                         .push(span.clone());
                 }
-            }
-            if trait_id != NULL {
-                if let Some(trait_id) = abs_ref_id(trait_id, analysis, project_analysis) {
+                if let Some(trait_id) = to_id {
                     trace!("record impl for trait {:?} {}", span, trait_id);
                     analysis
                         .impls
                         .entry(trait_id)
                         .or_insert_with(|| vec![])
-                        .push(span);
+                        .push(span.clone());
                 }
             }
+
+            if let Some(impl_id) = impl_id {
+                impl_types.insert(impl_id, (from_id, to_id));
+            }
+
+            // Inherent impls have no trait, so are not part of the type hierarchy.
+            if let (Some(from), Some(to)) = (from_id, to_id) {
+                trace!("record relation {:?} {} -> {}", kind, from, to);
+                let relation = Relation { kind, span, from, to };
+                analysis
+                    .relations
+                    .entry(from)
+                    .or_insert_with(|| vec![])
+                    .push(relation.clone());
+                analysis
+                    .relations
+                    .entry(to)
+                    .or_insert_with(|| vec![])
+                    .push(relation);
+            }
+        }
+
+        for i in impls {
+            let (self_id, trait_id) = impl_types.get(&i.id).cloned().unwrap_or((None, None));
+            let span = lower_span(&i.span, &self.base_dir, &self.path_rewrite);
+            let imp = Impl {
+                span: span.clone(),
+                parent: i.parent.map(|id| self.id_from_compiler_id(&id)),
+                self_id,
+                trait_id,
+                children: i.children.iter().map(|id| self.id_from_compiler_id(id)).collect(),
+            };
+            trace!("record impl block {:?}", imp);
+            analysis
+                .impls_per_file
+                .entry(span.file)
+                .or_insert_with(|| vec![])
+                .push(imp);
         }
     }
 
-    // fn lower_sig(&self, raw_sig: &raw::Signature, base_dir: &Path) -> Signature {
-    //     Signature {
-    //         span: lower_span(&raw_sig.span, base_dir, &self.path_rewrite),
-    //         text: raw_sig.text.clone(),
-    //         ident_start: raw_sig.ident_start as u32,
-    //         ident_end: raw_sig.ident_end as u32,
-    //         defs: raw_sig.defs.iter().map(|se| self.lower_sig_element(se)).collect(),
-    //         refs: raw_sig.refs.iter().map(|se| self.lower_sig_element(se)).collect(),
-    //     }
-    // }
-
-    // fn lower_sig_element(&self, raw_se: &raw::SigElement) -> SigElement {
-    //     SigElement {
-    //         id: self.id_from_compiler_id(&raw_se.id),
-    //         start: raw_se.start,
-    //         end: raw_se.end,
-    //     }
-    // }
+    fn lower_sig(&self, raw_sig: &raw::Signature) -> Signature {
+        Signature {
+            text: raw_sig.text.clone(),
+            defs: raw_sig.defs.iter().map(|se| self.lower_sig_element(se)).collect(),
+            refs: raw_sig.refs.iter().map(|se| self.lower_sig_element(se)).collect(),
+        }
+    }
+
+    fn lower_sig_element(&self, raw_se: &raw::SigElement) -> SigElement {
+        SigElement {
+            id: self.id_from_compiler_id(&raw_se.id),
+            start: raw_se.start,
+            end: raw_se.end,
+        }
+    }
 
     /// Recreates resulting crate-local (`u32`, `u32`) id from compiler
     /// to a global `u64` `Id`, mapping from a local to global crate id.
@@ -496,12 +902,90 @@ impl<'a> CrateReader<'a> {
     }
 }
 
-fn abs_ref_id<L: AnalysisLoader>(
+// The start of the def of each item in the crate, per file and sorted by
+// position, with whether the item is a function or method and whether it is
+// nested inside a function body. Generic params, fields, variants and locals
+// are not items here; none of them have a body of their own.
+fn items_per_file(analysis: &PerCrateAnalysis) -> HashMap<PathBuf, Vec<Item>> {
+    let functions: HashSet<&str> = analysis
+        .defs
+        .values()
+        .filter(|def| def.kind == DefKind::Function || def.kind == DefKind::Method)
+        .map(|def| &*def.qualname)
+        .collect();
+
+    let mut result = HashMap::new();
+    for (id, def) in &analysis.defs {
+        match def.kind {
+            DefKind::Local |
+            DefKind::Type |
+            DefKind::Field |
+            DefKind::TupleVariant |
+            DefKind::StructVariant => continue,
+            _ => {}
+        }
+        // The qualname of an item in a function body extends the function's
+        // qualname, e.g., `foo::bar` for `fn bar` inside `fn foo`.
+        let nested = match def.qualname.rfind("::") {
+            Some(i) => functions.contains(&def.qualname[..i]),
+            None => false,
+        };
+        result
+            .entry(def.span.file.clone())
+            .or_insert_with(|| vec![])
+            .push(Item {
+                start: def.span.range.start(),
+                id: *id,
+                is_function: def.kind == DefKind::Function || def.kind == DefKind::Method,
+                nested,
+            });
+    }
+    for items in result.values_mut() {
+        items.sort_by_key(|item| item.start);
+    }
+    result
+}
+
+struct Item {
+    start: span::Position<span::ZeroIndexed>,
+    id: Id,
+    is_function: bool,
+    nested: bool,
+}
+
+// The function or method whose body contains `span`. We don't have the spans
+// of item bodies, only of their names, so we treat the item whose name most
+// closely precedes `span` as the innermost item containing it. There is only
+// a caller if that item is a function or method. If it is nested inside a
+// function body, `span` may be after the nested item but still in the outer
+// function, so we can't tell which function is the caller and give up.
+fn enclosing_function(items: &HashMap<PathBuf, Vec<Item>>, span: &Span) -> Option<Id> {
+    let items = items.get(&span.file)?;
+    let start = span.range.start();
+    let preceding = items
+        .binary_search_by(|item| if item.start < start {
+            Ordering::Less
+        } else {
+            Ordering::Greater
+        })
+        .unwrap_err();
+    if preceding == 0 {
+        return None;
+    }
+    let item = &items[preceding - 1];
+    if item.is_function && !item.nested {
+        Some(item.id)
+    } else {
+        None
+    }
+}
+
+fn abs_ref_id(
     id: Id,
     analysis: &PerCrateAnalysis,
-    project_analysis: &AnalysisHost<L>,
+    ctx: &LoweringContext,
 ) -> Option<Id> {
-    if project_analysis.has_def(id) || analysis.defs.contains_key(&id) {
+    if ctx.known_defs.contains(&id) || analysis.defs.contains_key(&id) {
         return Some(id);
     }
 
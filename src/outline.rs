@@ -0,0 +1,168 @@
+// Copyright 2018 The RLS Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use analysis::{Def, Impl};
+use raw::DefKind;
+use {Id, Span};
+
+use std::collections::HashMap;
+
+/// A node in the outline of a file, see `AnalysisHost::outline`.
+#[derive(Debug, Clone)]
+pub struct OutlineNode {
+    pub item: OutlineItem,
+    /// The nodes nested in this one, in source order.
+    pub children: Vec<OutlineNode>,
+}
+
+#[derive(Debug, Clone)]
+pub enum OutlineItem {
+    Def(Id, Def),
+    Impl(Impl),
+}
+
+impl OutlineItem {
+    pub fn span(&self) -> &Span {
+        match *self {
+            OutlineItem::Def(_, ref def) => &def.span,
+            OutlineItem::Impl(ref imp) => &imp.span,
+        }
+    }
+}
+
+// Builds the outline of a file from its defs and impls.
+//
+// Def spans are only the spans of the defs' names, so nesting is taken from
+// the impls' children and the defs' parents, not from the spans. Items whose
+// parent is not in the file are at the top level. Locals and the file's own
+// (nameless) module are not included.
+crate fn build(defs: Vec<(Id, Def)>, impls: Vec<Impl>) -> Vec<OutlineNode> {
+    let defs: Vec<_> = defs
+        .into_iter()
+        .filter(|&(_, ref def)| def.kind != DefKind::Local && !def.name.is_empty())
+        .collect();
+    let def_nodes: HashMap<Id, usize> =
+        defs.iter().enumerate().map(|(i, &(id, _))| (id, i)).collect();
+
+    let mut parents: Vec<Option<usize>> = vec![None; defs.len() + impls.len()];
+    for (i, &(_, ref def)) in defs.iter().enumerate() {
+        parents[i] = def.parent.and_then(|p| def_nodes.get(&p).cloned());
+    }
+    for (i, imp) in impls.iter().enumerate() {
+        let node = defs.len() + i;
+        parents[node] = imp.parent.and_then(|p| def_nodes.get(&p).cloned());
+        for child in &imp.children {
+            if let Some(&c) = def_nodes.get(child) {
+                parents[c] = Some(node);
+            }
+        }
+    }
+
+    let mut items: Vec<Option<OutlineItem>> = defs
+        .into_iter()
+        .map(|(id, def)| Some(OutlineItem::Def(id, def)))
+        .chain(impls.into_iter().map(|imp| Some(OutlineItem::Impl(imp))))
+        .collect();
+    let mut children: Vec<Vec<usize>> = vec![vec![]; items.len()];
+    let mut roots = vec![];
+    for (node, parent) in parents.into_iter().enumerate() {
+        match parent {
+            Some(parent) => children[parent].push(node),
+            None => roots.push(node),
+        }
+    }
+
+    nodes(&roots, &mut items, &children)
+}
+
+fn nodes(
+    indices: &[usize],
+    items: &mut [Option<OutlineItem>],
+    children: &[Vec<usize>],
+) -> Vec<OutlineNode> {
+    let mut result: Vec<_> = indices
+        .iter()
+        .filter_map(|&i| {
+            let item = items[i].take()?;
+            Some(OutlineNode { item, children: nodes(&children[i], items, children) })
+        })
+        .collect();
+    result.sort_by_key(|n| n.item.span().range.start());
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use span::{Column, Row};
+    use std::path::Path;
+
+    fn span(row: u32) -> Span {
+        let row = Row::new_zero_indexed(row);
+        let (start, end) = (Column::new_zero_indexed(0), Column::new_zero_indexed(1));
+        Span::new(row, row, start, end, Path::new("lib.rs"))
+    }
+
+    fn def(name: &str, kind: DefKind, row: u32, parent: Option<u64>) -> (Id, Def) {
+        (
+            Id(row as u64),
+            Def {
+                kind,
+                span: span(row),
+                name: name.to_owned(),
+                qualname: name.to_owned(),
+                distro_crate: false,
+                parent: parent.map(Id),
+                value: String::new(),
+                docs: String::new(),
+                sig: None,
+                attributes: vec![],
+            },
+        )
+    }
+
+    fn names(nodes: &[OutlineNode]) -> Vec<String> {
+        nodes
+            .iter()
+            .map(|n| {
+                let name = match n.item {
+                    OutlineItem::Def(_, ref def) => def.name.clone(),
+                    OutlineItem::Impl(_) => "impl".to_owned(),
+                };
+                if n.children.is_empty() {
+                    name
+                } else {
+                    format!("{}({})", name, names(&n.children).join(" "))
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_build() {
+        let defs = vec![
+            def("", DefKind::Mod, 0, None),
+            def("f", DefKind::Field, 2, Some(1)),
+            def("Foo", DefKind::Struct, 1, None),
+            def("m", DefKind::Mod, 7, None),
+            def("new", DefKind::Method, 5, None),
+            def("x", DefKind::Local, 6, None),
+            def("Bar", DefKind::Struct, 8, Some(7)),
+        ];
+        let impls = vec![
+            Impl {
+                span: span(4),
+                parent: None,
+                self_id: Some(Id(1)),
+                trait_id: None,
+                children: vec![Id(5)],
+            },
+        ];
+        assert_eq!(names(&build(defs, impls)), ["Foo(f)", "impl(new)", "m(Bar)"]);
+    }
+}
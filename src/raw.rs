@@ -9,11 +9,14 @@
 use {AnalysisLoader, Blacklist};
 use json;
 use listings::{DirectoryListing, ListingKind};
-pub use data::{CratePreludeData, Def, DefKind, GlobalCrateId as CrateId, Import,
-               Ref, Relation, RelationKind, SigElement, Signature, SpanData};
+pub use data::{CratePreludeData, Def, DefKind, GlobalCrateId as CrateId, Impl, Import,
+               ImportKind, MacroRef, Ref, RefKind, Relation, RelationKind, SigElement, Signature,
+               SpanData};
 use data::Analysis;
 use data::config::Config;
 
+use rayon::prelude::*;
+
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{self, Read};
@@ -48,15 +51,15 @@ pub fn read_analysis_from_files<L: AnalysisLoader>(
     crate_timestamps: HashMap<PathBuf, SystemTime>,
     crate_blacklist: Blacklist,
 ) -> Vec<Crate> {
-    let mut result = vec![];
+    let t = Instant::now();
 
+    // Find the files which need reading, then read and decode them in parallel.
+    let mut to_read = vec![];
     loader.search_directories()
         .iter()
         .inspect(|dir| trace!("Considering analysis files at {}", dir.path.display()))
         .filter_map(|dir| DirectoryListing::from_path(&dir.path).ok().map(|list| (dir, list)))
         .for_each(|(dir, listing)| {
-            let t = Instant::now();
-
             for l in listing.files {
                 info!("Considering {:?}", l);
                 if let ListingKind::File(ref time) = l.kind {
@@ -67,23 +70,30 @@ pub fn read_analysis_from_files<L: AnalysisLoader>(
                     let path = dir.path.join(&l.name);
                     let is_fresh = crate_timestamps.get(&path).map_or(true, |t| time > t);
                     if is_fresh {
-                        read_crate_data(&path).map(|analysis| {
-                            result.push(Crate::new(analysis, *time, Some(path), dir.prefix_rewrite.clone()));
-                        });
+                        to_read.push((path, *time, dir.prefix_rewrite.clone()));
                     }
                 }
             }
-
-            let d = t.elapsed();
-            info!(
-                "reading {} crates from {} in {}.{:09}s",
-                result.len(),
-                dir.path.display(),
-                d.as_secs(),
-                d.subsec_nanos()
-            );
         });
 
+    let file_count = to_read.len();
+    let result: Vec<_> = to_read
+        .into_par_iter()
+        .filter_map(|(path, time, prefix_rewrite)| {
+            read_crate_data(&path)
+                .map(|analysis| Crate::new(analysis, time, Some(path), prefix_rewrite))
+        })
+        .collect();
+
+    let d = t.elapsed();
+    info!(
+        "reading {} crates from {} files in {}.{:09}s",
+        result.len(),
+        file_count,
+        d.as_secs(),
+        d.subsec_nanos()
+    );
+
     result
 }
 
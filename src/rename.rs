@@ -0,0 +1,135 @@
+// Copyright 2018 The RLS Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use analysis::{Analysis, Ref};
+use raw::name_space_for_def_kind;
+use {AError, AResult, Id, Span};
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// The edits needed to rename a def, see `AnalysisHost::plan_rename`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenamePlan {
+    pub id: Id,
+    /// The spans to replace with the new name (the def's own span and those of
+    /// all refs to it), grouped by file. Files are sorted by path and spans by
+    /// position.
+    pub edits: Vec<(PathBuf, Vec<Span>)>,
+}
+
+/// Why a rename cannot be done safely, and the spans which are the problem.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenameRefusal {
+    pub reason: RenameRefusalReason,
+    pub spans: Vec<Span>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenameRefusalReason {
+    /// The new name is not an identifier.
+    InvalidName,
+    /// The def is in a Rust distro crate (e.g., std), so can't be edited.
+    DistroCrate,
+    /// The def is imported under an alias somewhere, and renaming it would
+    /// rename the alias too.
+    AliasedImport,
+    /// Some refs (e.g., shorthand field patterns) are to more than one def,
+    /// so can't be renamed without renaming the other def too.
+    AmbiguousRefs,
+    /// A def with the same parent and in the same namespace already has the
+    /// new name.
+    NameCollision,
+}
+
+crate fn plan(analysis: &Analysis, span: &Span, new_name: &str) -> AResult<RenamePlan> {
+    let refuse = |reason, spans| Err(AError::RenameRefused(RenameRefusal { reason, spans }));
+
+    let id = match analysis.ref_for_span(span)? {
+        Ref::Id(id) => id,
+        _ => return refuse(RenameRefusalReason::AmbiguousRefs, vec![span.clone()]),
+    };
+    let def = analysis.with_defs(id, |def| def.clone())?;
+    if !is_identifier(new_name) {
+        return refuse(RenameRefusalReason::InvalidName, vec![]);
+    }
+    if def.distro_crate {
+        return refuse(RenameRefusalReason::DistroCrate, vec![def.span]);
+    }
+    if analysis.is_imported_with_alias(id) {
+        return refuse(RenameRefusalReason::AliasedImport, vec![def.span]);
+    }
+
+    let refs = analysis
+        .with_ref_spans(id, |refs| Some(refs.iter().map(|&(ref s, _)| s.clone()).collect()))
+        .unwrap_or_else(Vec::new);
+    let ambiguous: Vec<_> = refs
+        .iter()
+        .chain(Some(span))
+        .filter(|s| match analysis.ref_for_span(s) {
+            Ok(Ref::Id(_)) | Err(_) => false,
+            Ok(_) => true,
+        })
+        .cloned()
+        .collect();
+    if !ambiguous.is_empty() {
+        return refuse(RenameRefusalReason::AmbiguousRefs, ambiguous);
+    }
+
+    if let Some(parent) = def.parent {
+        let name_space = name_space_for_def_kind(def.kind);
+        let collisions: Vec<_> = analysis
+            .for_each_child(parent, |child_id, child| {
+                if child_id != id
+                    && child.name == new_name
+                    && name_space_for_def_kind(child.kind) == name_space
+                {
+                    Some(child.span.clone())
+                } else {
+                    None
+                }
+            })
+            .unwrap_or_else(Vec::new)
+            .into_iter()
+            .filter_map(|s| s)
+            .collect();
+        if !collisions.is_empty() {
+            return refuse(RenameRefusalReason::NameCollision, collisions);
+        }
+    }
+
+    let mut edits: BTreeMap<PathBuf, Vec<Span>> = BTreeMap::new();
+    for s in Some(def.span).into_iter().chain(refs) {
+        edits.entry(s.file.clone()).or_insert_with(Vec::new).push(s);
+    }
+    for spans in edits.values_mut() {
+        spans.sort_by_key(|s| s.range.start());
+        spans.dedup();
+    }
+    Ok(RenamePlan { id, edits: edits.into_iter().collect() })
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    name != "_" && chars.all(|c| c.is_alphanumeric() || c == '_') && !KEYWORDS.contains(&name)
+}
+
+// Strict and reserved keywords, which can't be used as identifiers. Includes
+// those reserved by the 2018 edition.
+const KEYWORDS: &[&str] = &[
+    "Self", "abstract", "alignof", "as", "async", "await", "become", "box", "break", "const",
+    "continue", "crate", "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for",
+    "if", "impl", "in", "let", "loop", "macro", "match", "mod", "move", "mut", "offsetof",
+    "override", "priv", "proc", "pub", "pure", "ref", "return", "self", "sizeof", "static",
+    "struct", "super", "trait", "true", "try", "type", "typeof", "unsafe", "unsized", "use",
+    "virtual", "where", "while", "yield",
+];
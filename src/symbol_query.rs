@@ -1,44 +1,116 @@
-use fst::{self, Streamer};
+use {AError, AResult, Id};
+
+use fst::{self, Automaton, Streamer};
+use fst_levenshtein::Levenshtein;
+use fst_regex::Regex;
+use std::cmp::Reverse;
 
 /// `SymbolQuery` specifies the preficate for filtering symbols by name.
 ///
 /// All matching is case-insensitive. Filtering by prefix or by subsequence
-/// is supported, subsequence being a good default choice.
+/// is supported, subsequence being a good default choice. Fuzzy queries match
+/// like subsequence queries, but the results are ranked by `score`. Names can
+/// also be matched by a regex, a glob, or within an edit distance.
+///
+/// A query may be qualified with a path, e.g., `std::collections::Hash`. Only
+/// the last segment is matched against symbol names; each of the earlier
+/// segments must equal a segment of the symbol's qualified name, in order
+/// (but not necessarily contiguously, so `std::HashMap` will find
+/// `std::collections::hash::map::HashMap`).
 ///
 /// As the number of results might be huge, consider the `limit` hint,
 /// which serves as *approximate* limit on the number of results returned.
 ///
-/// To implement async streaming/pagination, use `greater_than` together with
-/// `limit`.
+/// To implement async streaming/pagination, use `query_defs_page`, for which
+/// `limit` is the exact page size.
 #[derive(Debug)]
 pub struct SymbolQuery {
     query_string: String,
+    // The query before lowercasing, used for scoring.
+    original: String,
+    // Any path segments before the last, lowercased.
+    qualifier: Vec<String>,
     mode: Mode,
     limit: usize,
     greater_than: String,
 }
 
-#[derive(Debug, Clone, Copy)]
-enum Mode { Prefix, Subsequence }
+#[derive(Debug)]
+enum Mode {
+    Prefix,
+    Subsequence,
+    Fuzzy,
+    // Also used for globs, which are compiled to the same automaton.
+    Regex(Regex),
+    Levenshtein(Levenshtein),
+}
 
 impl SymbolQuery {
-    fn new(query_string: String, mode: Mode) -> SymbolQuery {
+    fn new(query_string: &str, mode: Mode) -> SymbolQuery {
+        let mut segments: Vec<_> = query_string.split("::").collect();
+        let name = segments.pop().unwrap_or("");
         SymbolQuery {
-            query_string, mode,
+            query_string: name.to_lowercase(),
+            original: name.to_owned(),
+            qualifier: segments
+                .into_iter()
+                .filter(|s| !s.is_empty())
+                .map(|s| s.to_lowercase())
+                .collect(),
+            mode,
             limit: usize::max_value(),
             greater_than: String::new()
         }
     }
 
     pub fn subsequence(query_string: &str) -> SymbolQuery {
-        SymbolQuery::new(query_string.to_lowercase(), Mode::Subsequence)
+        SymbolQuery::new(query_string, Mode::Subsequence)
     }
 
     pub fn prefix(query_string: &str) -> SymbolQuery {
-        SymbolQuery::new(query_string.to_lowercase(), Mode::Prefix)
+        SymbolQuery::new(query_string, Mode::Prefix)
+    }
+
+    /// Like `subsequence`, but the results are ranked by `score`, best first.
+    /// `limit` is exact and is applied after ranking, so all matches are
+    /// scored.
+    pub fn fuzzy(query_string: &str) -> SymbolQuery {
+        SymbolQuery::new(query_string, Mode::Fuzzy)
+    }
+
+    /// Matches names against a regex, which must match the whole name. The
+    /// syntax is that of the `regex` crate, without anchors or word
+    /// boundaries. A regex can't be qualified with a path, so it must not
+    /// contain `::`.
+    pub fn regex(pattern: &str) -> AResult<SymbolQuery> {
+        if pattern.contains("::") {
+            return Err(AError::InvalidQuery("`::` in a regex".to_owned()));
+        }
+        let regex = case_insensitive_regex(pattern)?;
+        Ok(SymbolQuery::new(pattern, Mode::Regex(regex)))
+    }
+
+    /// Matches names against a glob, e.g., `try_*` or `*_mut`, where `*`
+    /// matches any sequence, `?` any single character, and `[..]` is a
+    /// character class as for `regex`.
+    pub fn glob(pattern: &str) -> AResult<SymbolQuery> {
+        let regex = case_insensitive_regex(&glob_to_regex(last_segment(pattern)))?;
+        Ok(SymbolQuery::new(pattern, Mode::Regex(regex)))
     }
 
+    /// Matches names within `distance` edits (insertions, deletions or
+    /// substitutions) of `name`. Fails if `distance` is too large for `name`
+    /// to build the automaton.
+    pub fn levenshtein(name: &str, distance: u32) -> AResult<SymbolQuery> {
+        let lev = Levenshtein::new(&last_segment(name).to_lowercase(), distance)
+            .map_err(|e| AError::InvalidQuery(e.to_string()))?;
+        Ok(SymbolQuery::new(name, Mode::Levenshtein(lev)))
+    }
+
+    /// A limit of zero means no limit (as without calling `limit`), so that a
+    /// page is never empty when there are more results.
     pub fn limit(self, limit: usize) -> SymbolQuery {
+        let limit = if limit == 0 { usize::max_value() } else { limit };
         SymbolQuery { limit, ..self }
     }
 
@@ -50,16 +122,45 @@ impl SymbolQuery {
     where
         I: Iterator<Item=&'a fst::Map>,
     {
+        self.build_stream_from(fsts, None)
+    }
+
+    // Like `build_stream`, but if there is a `cursor`, the stream starts at
+    // (and includes) the cursor's key rather than after `greater_than`.
+    // Fuzzy queries are ranked, not in key order, so always start at the
+    // beginning.
+    pub(crate) fn build_stream_from<'a, I>(
+        &'a self,
+        fsts: I,
+        cursor: Option<&Cursor>,
+    ) -> fst::map::Union<'a>
+    where
+        I: Iterator<Item=&'a fst::Map>,
+    {
+        let from = if self.is_fuzzy() { None } else { cursor.map(|c| &c.key[..]) };
         let mut stream = fst::map::OpBuilder::new();
-        let automaton = QueryAutomaton { query: &self.query_string, mode: self.mode };
+        let automaton = QueryAutomaton { query: &self.query_string, mode: &self.mode };
         for fst in fsts {
-            stream = stream.add(
-                fst.search(automaton).gt(&self.greater_than)
-            );
+            stream = match self.mode {
+                Mode::Regex(ref regex) => stream.add(self.bound(fst.search(regex), from)),
+                Mode::Levenshtein(ref lev) => stream.add(self.bound(fst.search(lev), from)),
+                _ => stream.add(self.bound(fst.search(automaton), from)),
+            };
         }
         stream.union()
     }
 
+    fn bound<'a, A: Automaton>(
+        &self,
+        builder: fst::map::StreamBuilder<'a, A>,
+        from: Option<&str>,
+    ) -> fst::map::StreamBuilder<'a, A> {
+        match from {
+            Some(key) => builder.ge(key),
+            None => builder.gt(&self.greater_than),
+        }
+    }
+
     pub(crate) fn search_stream<F, T>(&self, mut stream: fst::map::Union, f: F) -> Vec<T>
     where
         F: Fn(&mut Vec<T>, &fst::map::IndexedValue),
@@ -69,12 +170,267 @@ impl SymbolQuery {
             for e in entries {
                 f(&mut res, e);
             }
-            if res.len() >= self.limit {
+            if !self.is_fuzzy() && res.len() >= self.limit {
                 break;
             }
         }
         res
     }
+
+    pub(crate) fn is_fuzzy(&self) -> bool {
+        match self.mode {
+            Mode::Fuzzy => true,
+            _ => false,
+        }
+    }
+
+    // Whether a symbol's qualified name satisfies the query's path qualifier.
+    // Qualified names may include generics (e.g., `std<HashMap<K, V>>::new`),
+    // so we split them on any non-identifier characters.
+    pub(crate) fn matches_qualname(&self, qualname: &str) -> bool {
+        if self.qualifier.is_empty() {
+            return true;
+        }
+        let mut segments = qualname
+            .split(|c: char| !c.is_alphanumeric() && c != '_')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>();
+        // The last segment is the symbol's own name.
+        segments.pop();
+        let mut segments = segments.into_iter();
+        self.qualifier
+            .iter()
+            .all(|q| segments.any(|s| s.to_lowercase() == *q))
+    }
+
+    // Sorts fuzzy query results by score, best first, and applies the limit.
+    // Results of other queries are left in lexical order.
+    pub(crate) fn rank<T, F>(&self, results: &mut Vec<T>, score: F)
+    where
+        F: Fn(&T) -> u32,
+    {
+        if self.is_fuzzy() {
+            results.sort_by(|a, b| score(b).cmp(&score(a)));
+            results.truncate(self.limit);
+        }
+    }
+
+    /// Scores how well `name` matches the query, higher is better, or returns
+    /// `None` if the query is not a (case-insensitive) subsequence of `name`.
+    ///
+    /// Query characters which match at the start of a word in `name` (in
+    /// CamelCase or snake_case), which follow the previous match directly, or
+    /// which match case exactly all score extra. Gaps between matches and
+    /// unmatched characters in `name` cost a little.
+    pub fn score(&self, name: &str) -> Option<u32> {
+        self.align(name).map(|(score, _)| score)
+    }
+
+    /// The byte ranges of `name` which matched the query, e.g., for
+    /// highlighting. Adjacent matched characters are merged into one range.
+    /// Regex, glob and Levenshtein queries have no ranges, since their
+    /// automata only say whether a name matched, not which parts of it did.
+    pub fn match_ranges(&self, name: &str) -> Vec<(usize, usize)> {
+        let offsets: Vec<(usize, usize)> =
+            name.char_indices().map(|(i, c)| (i, i + c.len_utf8())).collect();
+        let matched: Vec<usize> = match self.mode {
+            Mode::Prefix => {
+                (0..::std::cmp::min(self.original.chars().count(), offsets.len())).collect()
+            }
+            Mode::Subsequence | Mode::Fuzzy => self.align(name).map_or(vec![], |(_, m)| m),
+            Mode::Regex(_) | Mode::Levenshtein(_) => vec![],
+        };
+
+        let mut ranges: Vec<(usize, usize)> = vec![];
+        for i in matched {
+            let (start, end) = offsets[i];
+            match ranges.last_mut() {
+                Some(last) if last.1 == start => last.1 = end,
+                _ => ranges.push((start, end)),
+            }
+        }
+        ranges
+    }
+
+    // Finds the best scoring alignment of the query with `name` (see `score`),
+    // returning the score and the indices of the matched characters in `name`.
+    fn align(&self, name: &str) -> Option<(u32, Vec<usize>)> {
+        const MATCH: i32 = 16;
+        const WORD_START: i32 = 16;
+        const CONSECUTIVE: i32 = 16;
+        const EXACT_CASE: i32 = 2;
+        const GAP: i32 = 4;
+        const UNMATCHED: i32 = 1;
+
+        let query: Vec<char> = self.original.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+        if query.is_empty() {
+            return Some((0, vec![]));
+        }
+        if query.len() > name.len() {
+            return None;
+        }
+
+        let lower = |c: char| c.to_lowercase().next().unwrap_or(c);
+        let is_word_start = |j: usize| {
+            if j == 0 {
+                return true;
+            }
+            let (prev, cur) = (name[j - 1], name[j]);
+            (!prev.is_alphanumeric() && cur.is_alphanumeric())
+                || (cur.is_uppercase() && !prev.is_uppercase())
+        };
+
+        // best[j] is the best score for the query so far with its last
+        // character matched at name[j]; back[i][j] is where the previous
+        // query character was matched in that case.
+        const NONE: i32 = i32::min_value();
+        let mut best = vec![NONE; name.len()];
+        let mut back = Vec::with_capacity(query.len());
+        for (i, &q) in query.iter().enumerate() {
+            let mut next = vec![NONE; name.len()];
+            let mut from = vec![0; name.len()];
+            for j in i..name.len() {
+                if lower(name[j]) != lower(q) {
+                    continue;
+                }
+                let mut score = MATCH;
+                if is_word_start(j) {
+                    score += WORD_START;
+                }
+                if name[j] == q {
+                    score += EXACT_CASE;
+                }
+                let prev = if i == 0 {
+                    Some((0, 0))
+                } else {
+                    (i - 1..j)
+                        .filter(|&k| best[k] != NONE)
+                        .map(|k| (best[k] + if k + 1 == j { CONSECUTIVE } else { -GAP }, k))
+                        .max_by_key(|&(s, _)| s)
+                };
+                if let Some((prev, k)) = prev {
+                    next[j] = prev + score;
+                    from[j] = k;
+                }
+            }
+            best = next;
+            back.push(from);
+        }
+
+        let (score, mut j) = best.into_iter()
+            .enumerate()
+            .filter(|&(_, s)| s != NONE)
+            .map(|(j, s)| (s, j))
+            .max_by_key(|&(s, _)| s)?;
+        let mut matched = vec![0; query.len()];
+        for i in (0..query.len()).rev() {
+            matched[i] = j;
+            j = back[i][j];
+        }
+
+        let unmatched = (name.len() - query.len()) as i32;
+        Some((::std::cmp::max(score - unmatched * UNMATCHED, 0) as u32, matched))
+    }
+}
+
+/// A position in the results of a `SymbolQuery`, returned with each page by
+/// `AnalysisHost::query_defs_page` and passed back to get the next page.
+///
+/// Results are ordered by name (lowercased) and then by id; results of fuzzy
+/// queries are ordered by rank first.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cursor {
+    rank: Reverse<u32>,
+    key: String,
+    id: Id,
+}
+
+impl Cursor {
+    pub(crate) fn new(rank: u32, key: &str, id: Id) -> Cursor {
+        Cursor { rank: Reverse(rank), key: key.to_owned(), id }
+    }
+}
+
+// Collects one page of results, which must be pushed in cursor order. Results
+// at or before `after` are skipped.
+pub(crate) struct Page<T> {
+    after: Option<Cursor>,
+    size: usize,
+    results: Vec<T>,
+    last: Option<Cursor>,
+    next: Option<Cursor>,
+}
+
+impl<T> Page<T> {
+    // A page of `query.limit` results.
+    pub(crate) fn new(query: &SymbolQuery, after: Option<Cursor>) -> Page<T> {
+        Page { after, size: query.limit, results: vec![], last: None, next: None }
+    }
+
+    // Returns false once the page is full and another result has been pushed,
+    // i.e., there is a next page. There is no point pushing any more.
+    pub(crate) fn push(&mut self, position: Cursor, result: T) -> bool {
+        if self.after.as_ref().map_or(false, |after| position <= *after) {
+            return true;
+        }
+        if self.results.len() >= self.size {
+            self.next = self.last.take();
+            return false;
+        }
+        self.results.push(result);
+        self.last = Some(position);
+        true
+    }
+
+    // The results and the cursor for the next page, if there is one.
+    pub(crate) fn finish(self) -> (Vec<T>, Option<Cursor>) {
+        (self.results, self.next)
+    }
+}
+
+// The keys of `def_fst` are lowercased names, so the regex must ignore case.
+// We compile the pattern as written rather than lowercasing it first, since
+// lowercasing would change its meaning (e.g., `\W` to `\w`).
+fn case_insensitive_regex(pattern: &str) -> AResult<Regex> {
+    Regex::new(&format!("(?i){}", pattern)).map_err(|e| AError::InvalidQuery(e.to_string()))
+}
+
+// The name being queried for, i.e., the last segment of a path.
+fn last_segment(query_string: &str) -> &str {
+    query_string.rsplit("::").next().unwrap_or("")
+}
+
+// Translates a glob (see `SymbolQuery::glob`) to a regex.
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::new();
+    let mut in_class = false;
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            _ if in_class => {
+                in_class = c != ']';
+                regex.push(c);
+            }
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '[' => {
+                in_class = true;
+                regex.push(c);
+                // `[!..]` is a negated class in a glob.
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    regex.push('^');
+                }
+            }
+            '\\' | '.' | '+' | '(' | ')' | '|' | ']' | '{' | '}' | '^' | '$' | '#' => {
+                regex.push('\\');
+                regex.push(c);
+            }
+            _ => regex.push(c),
+        }
+    }
+    regex
 }
 
 /// See http://docs.rs/fst for how we implement query processing.
@@ -89,7 +445,7 @@ impl SymbolQuery {
 #[derive(Clone, Copy)]
 struct QueryAutomaton<'a> {
     query: &'a str,
-    mode: Mode,
+    mode: &'a Mode,
 }
 
 const NO_MATCH: usize = !0;
@@ -115,9 +471,9 @@ impl<'a> fst::Automaton for QueryAutomaton<'a> {
         if byte == self.query.as_bytes()[state] {
             return state + 1;
         }
-        match self.mode {
+        match *self.mode {
             Mode::Prefix => NO_MATCH,
-            Mode::Subsequence => state,
+            _ => state,
         }
     }
 
@@ -183,5 +539,86 @@ mod tests {
         check(SymbolQuery::subsequence("an").limit(2).greater_than("canopus"), &[
             "lalandry",
         ]);
+
+        check(SymbolQuery::fuzzy("an"), &[
+            "agena", "anektor", "antares", "canopus", "lalandry"
+        ]);
+    }
+
+    #[test]
+    fn test_patterns() {
+        check(SymbolQuery::regex("a.*r").unwrap(), &["agreetor", "anektor"]);
+        check(SymbolQuery::regex("(ca|ve).+").unwrap(), &["canopus", "capella", "vega"]);
+        check(SymbolQuery::regex("CA.*").unwrap().limit(1), &["canopus"]);
+        check(SymbolQuery::regex("[A-C]\\W*a.*").unwrap(), &["canopus", "capella"]);
+        check(SymbolQuery::regex("\\D+").unwrap().limit(1), &["agena"]);
+        check(SymbolQuery::glob("*in").unwrap(), &["duendin", "golubin"]);
+        check(SymbolQuery::glob("a?e*").unwrap(), &["agena", "anektor"]);
+        check(SymbolQuery::glob("[cv]*.+").unwrap(), &[]);
+        check(SymbolQuery::glob("[!a-r]*").unwrap(), &["spica", "vega"]);
+        check(SymbolQuery::glob("a[!g]*").unwrap(), &["algerib", "anektor", "antares", "arcturus"]);
+        check(SymbolQuery::levenshtein("vaga", 1).unwrap(), &["vega"]);
+        check(SymbolQuery::levenshtein("antres", 1).unwrap(), &["antares"]);
+        check(SymbolQuery::levenshtein("Spice", 2).unwrap(), &["spica"]);
+
+        assert!(SymbolQuery::regex("(a").is_err());
+        assert!(SymbolQuery::regex("a::b").is_err());
+        assert!(SymbolQuery::glob("[a").is_err());
+    }
+
+    #[test]
+    fn test_qualified() {
+        let q = SymbolQuery::prefix("std::collections::Hash");
+        assert!(q.matches_qualname("std::collections::hash::map::HashMap"));
+        assert!(q.matches_qualname("std::collections::HashSet"));
+        assert!(!q.matches_qualname("std::HashMap"));
+        assert!(!q.matches_qualname("std::collections"));
+        assert!(!q.matches_qualname("collections::std::HashMap"));
+
+        let q = SymbolQuery::prefix("::HashMap::new");
+        assert!(q.matches_qualname("std<HashMap<K, V, RandomState>>::new"));
+        assert!(!q.matches_qualname("std<HashSet<T, RandomState>>::new"));
+
+        let q = SymbolQuery::prefix("io::");
+        assert!(q.matches_qualname("std::io::error::Error"));
+        assert!(!q.matches_qualname("std::error::Error"));
+
+        // Only the last segment is matched against the names.
+        check(SymbolQuery::prefix("std::an"), &["anektor", "antares"]);
+    }
+
+    #[test]
+    fn test_score() {
+        let q = SymbolQuery::fuzzy("hmap");
+        assert!(q.score("HashMap").unwrap() > q.score("hashmap_internal_helper_map").unwrap());
+        assert!(q.score("hash_map").unwrap() > q.score("hashmap").unwrap());
+        assert_eq!(q.score("HashSet"), None);
+        assert_eq!(q.score("map"), None);
+
+        // Contiguous and exact-case matches score higher.
+        let q = SymbolQuery::fuzzy("Map");
+        assert!(q.score("Map").unwrap() > q.score("MyMap").unwrap());
+        assert!(q.score("BTreeMap").unwrap() > q.score("btreemap").unwrap());
+        assert!(q.score("map_err").unwrap() > q.score("m_a_p").unwrap());
+
+        let q = SymbolQuery::fuzzy("hmap").limit(3);
+        let mut results: Vec<_> = ["hashmap_internal_helper_map", "HashMap", "hash_map", "hmap"]
+            .iter()
+            .map(|name| (*name, q.score(name).unwrap()))
+            .collect();
+        q.rank(&mut results, |r| r.1);
+        let names: Vec<_> = results.iter().map(|r| r.0).collect();
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[0], "hmap");
+        assert!(!names.contains(&"hashmap_internal_helper_map"));
+    }
+
+    #[test]
+    fn test_match_ranges() {
+        assert_eq!(SymbolQuery::fuzzy("hmap").match_ranges("hash_map"), [(0, 1), (5, 8)]);
+        assert_eq!(SymbolQuery::subsequence("ab").match_ranges("äxab"), [(3, 5)]);
+        assert_eq!(SymbolQuery::prefix("äx").match_ranges("ÄxAb"), [(0, 3)]);
+        assert!(SymbolQuery::glob("*b").unwrap().match_ranges("äxab").is_empty());
+        assert!(SymbolQuery::subsequence("ba").match_ranges("ab").is_empty());
     }
 }
@@ -6,12 +6,20 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use {AnalysisHost, AnalysisLoader};
+use {AError, AnalysisHost, AnalysisLoader, ChangeEvent, ChangeSet, CrateChange, Cursor, DefFilter,
+     Id, ImportKind, OutlineItem, OutlineNode, RefKind, RenameRefusal, RenameRefusalReason, Span,
+     SymbolResult, SymbolQuery, Watcher};
+use data;
 use loader::SearchDirectory;
-use raw::DefKind;
+use raw::{CrateId, DefKind};
+use span::{Column, Row};
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 
 #[cfg(test)]
 extern crate env_logger;
@@ -19,11 +27,14 @@ extern crate env_logger;
 #[derive(Clone, new)]
 struct TestAnalysisLoader {
     path: PathBuf,
+    // Whether `reload` may do a soft reload once there is data loaded.
+    #[new(default)]
+    soft_reload: bool,
 }
 
 impl AnalysisLoader for TestAnalysisLoader {
     fn needs_hard_reload(&self, _path_prefix: &Path) -> bool {
-        true
+        !self.soft_reload
     }
 
     fn fresh_host(&self) -> AnalysisHost<Self> {
@@ -163,6 +174,47 @@ fn smoke() {
     ).unwrap();
 }
 
+#[test]
+fn test_lowering_is_deterministic() {
+    // Crates are lowered in parallel, but ids and crate numbers must not
+    // depend on which thread gets to a crate first.
+    fn lower() -> (HashMap<CrateId, u32>, Vec<(CrateId, Vec<String>)>) {
+        let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+            Path::new("test_data/multi_crate/save-analysis").to_owned(),
+        ));
+        host.reload(
+            Path::new("test_data/multi_crate"),
+            Path::new("test_data/multi_crate"),
+        ).unwrap();
+
+        let crate_map = host.master_crate_map.lock().unwrap().clone();
+        let analysis = host.analysis.lock().unwrap();
+        let mut crates: Vec<_> = analysis
+            .as_ref()
+            .unwrap()
+            .per_crate
+            .iter()
+            .map(|(crate_id, per_crate)| {
+                let mut defs: Vec<_> = per_crate
+                    .defs
+                    .iter()
+                    .map(|(id, def)| format!("{:?} {} {:?}", id, def.qualname, def.span))
+                    .collect();
+                defs.sort();
+                (crate_id.clone(), defs)
+            })
+            .collect();
+        crates.sort_by_key(|&(ref id, _)| (id.name.clone(), id.disambiguator));
+        (crate_map, crates)
+    }
+
+    let first = lower();
+    assert_eq!(first.1.len(), 3);
+    for _ in 0..3 {
+        assert_eq!(lower(), first);
+    }
+}
+
 #[test]
 fn test_hello() {
     // Simple program, a somewhat thorough test that we have all the defs and refs we expect.
@@ -226,6 +278,9 @@ fn test_hello() {
     assert_eq!(refs[0].range.row_start.0, 1);
     assert_eq!(refs[1].file, Path::new("test_data/hello/src/main.rs"));
     assert_eq!(refs[1].range.row_start.0, 2);
+    assert_eq!(host.ids(&refs[1]).unwrap(), [id]);
+    assert_eq!(host.goto_defs(&refs[1]).unwrap(), [refs[0].clone()]);
+    assert_eq!(host.show_type(&refs[1]).unwrap(), "&str");
 
     let defs = host.matching_defs("print_hello").unwrap();
     assert_eq!(defs.len(), 1);
@@ -273,6 +328,205 @@ fn test_hello() {
     assert_eq!(all_matches, expected_matches);
 }
 
+// Reads raw save-analysis data directly, so that tests can modify it before
+// passing it to `reload_from_analysis`.
+fn read_raw_analysis(path: &str) -> data::Analysis {
+    let mut buf = String::new();
+    File::open(path).unwrap().read_to_string(&mut buf).unwrap();
+    ::rustc_serialize::json::decode(&buf).unwrap()
+}
+
+#[test]
+fn test_signature() {
+    // None of our test data includes signatures, so add one by hand.
+    let mut analysis = read_raw_analysis("test_data/hello/save-analysis/hello.json");
+    for def in analysis.defs.iter_mut().filter(|d| d.name == "print_hello") {
+        def.sig = Some(data::Signature {
+            text: "fn print_hello()".to_owned(),
+            defs: vec![data::SigElement { id: def.id, start: 3, end: 14 }],
+            refs: vec![],
+        });
+    }
+
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/hello/no-save-analysis").to_owned(),
+    ));
+    host.reload_from_analysis(
+        vec![analysis],
+        Path::new("test_data/hello"),
+        Path::new("test_data/hello"),
+        &[],
+    ).unwrap();
+
+    let id = host.search_for_id("print_hello").unwrap()[0];
+    let spans = host.search("print_hello").unwrap();
+    assert_eq!(spans.len(), 2);
+    for span in &spans {
+        let sig = host.signature(span).unwrap();
+        assert_eq!(sig.text, "fn print_hello()");
+        assert_eq!(sig.defs.len(), 1);
+        assert_eq!(sig.defs[0].id, id);
+        assert_eq!(&sig.text[sig.defs[0].start..sig.defs[0].end], "print_hello");
+        assert!(sig.refs.is_empty());
+    }
+
+    let main = host.search("main").unwrap();
+    assert_eq!(host.signature(&main[0]).unwrap_err(), AError::NoSignature);
+}
+
+#[test]
+fn test_errors() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/hello/save-analysis").to_owned(),
+    ));
+    let main_id = Id::new(0);
+    assert_eq!(host.get_def(main_id).unwrap_err(), AError::NotLoaded);
+
+    host.reload(
+        Path::new("test_data/hello"),
+        Path::new("test_data/hello"),
+    ).unwrap();
+
+    let spans = host.search("main").unwrap();
+    let mut span = spans[0].clone();
+    span.range.row_start.0 += 1;
+    span.range.row_end.0 += 1;
+    assert_eq!(host.goto_def(&span), Err(AError::NoDefAtSpan(span.clone())));
+    assert_eq!(host.docs(&spans[0]), Err(AError::NoDocs));
+    assert_eq!(host.doc_url(&spans[0]), Err(AError::NotDistroCrate));
+
+    let unknown = Id::new(!0 - 1);
+    assert_eq!(host.get_def(unknown).unwrap_err(), AError::UnknownId(unknown));
+    assert_eq!(host.find_all_refs_by_id(unknown), Err(AError::UnknownId(unknown)));
+    assert_eq!(host.def_parents(unknown), Err(AError::UnknownId(unknown)));
+
+    let file = Path::new("test_data/hello/src/lib.rs");
+    assert_eq!(host.symbols(file).unwrap_err(), AError::NoDefsInFile(file.to_owned()));
+}
+
+#[test]
+fn test_position() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/hello/save-analysis").to_owned(),
+    ));
+    host.reload(
+        Path::new("test_data/hello"),
+        Path::new("test_data/hello"),
+    ).unwrap();
+
+    let file = Path::new("test_data/hello/src/main.rs");
+    let id = host.search_for_id("print_hello").unwrap()[0];
+    let spans = host.search("print_hello").unwrap();
+
+    // Anywhere within the call to `print_hello`.
+    for col in 4..15 {
+        let (span, r) = host.ref_at_position(
+            file,
+            Row::new_zero_indexed(6),
+            Column::new_zero_indexed(col),
+        ).unwrap();
+        assert_eq!(span, spans[1]);
+        assert_eq!(r.some_id(), id);
+        assert_eq!(host.goto_def(&span).unwrap(), spans[0]);
+    }
+    assert_eq!(
+        host.id_at_position(file, Row::new_zero_indexed(0), Column::new_zero_indexed(3)),
+        Ok(id)
+    );
+
+    // Outside any identifier, we find the enclosing module.
+    let root = host.def_roots().unwrap()[0].0;
+    for &(row, col) in &[(6, 3), (6, 15), (4, 0)] {
+        assert_eq!(
+            host.id_at_position(file, Row::new_zero_indexed(row), Column::new_zero_indexed(col)),
+            Ok(root)
+        );
+    }
+
+    let row = Row::new_zero_indexed(8);
+    let col = Column::new_zero_indexed(0);
+    assert_eq!(
+        host.id_at_position(file, row, col),
+        Err(AError::NoDefAtSpan(Span::new(row, row, col, col, file)))
+    );
+}
+
+#[test]
+fn test_cache() {
+    use std::sync::{mpsc, Mutex};
+
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/types/save-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/types"), Path::new("test_data/types"))
+        .unwrap();
+
+    let cache_path = env::temp_dir().join("rls-analysis-test_cache");
+    host.save_cache(&cache_path).unwrap();
+
+    let cached = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/types/save-analysis").to_owned(),
+    ));
+    cached.load_cache(&cache_path, Path::new("test_data/types")).unwrap();
+
+    for name in &["Foo", "TestTrait", "test_method", "FooEnum"] {
+        let ids = host.search_for_id(name).unwrap();
+        assert_eq!(cached.search_for_id(name).unwrap(), ids);
+        assert_eq!(cached.find_all_refs_by_id(ids[0]), host.find_all_refs_by_id(ids[0]));
+        for span in host.search(name).unwrap() {
+            assert_eq!(cached.goto_def(&span), host.goto_def(&span));
+        }
+    }
+    let names = |host: &AnalysisHost<TestAnalysisLoader>| {
+        host.matching_defs("t")
+            .unwrap()
+            .into_iter()
+            .map(|d| d.qualname)
+            .collect::<HashSet<_>>()
+    };
+    assert_eq!(names(&cached), names(&host));
+
+    // Crates whose data file has changed since it was read are dropped.
+    {
+        let mut analysis = host.analysis.lock().unwrap();
+        for c in analysis.as_mut().unwrap().per_crate.values_mut() {
+            c.timestamp = UNIX_EPOCH;
+        }
+    }
+    host.save_cache(&cache_path).unwrap();
+    let (sender, receiver) = mpsc::channel();
+    let sender = Mutex::new(sender);
+    cached.subscribe(move |e: &ChangeEvent| sender.lock().unwrap().send(e.clone()).unwrap())
+        .unwrap();
+    cached.load_cache(&cache_path, Path::new("test_data/types")).unwrap();
+    assert!(cached.search_for_id("Foo").unwrap().is_empty());
+    assert!(cached.def_roots().unwrap().is_empty());
+    // Subscribers are told about the dropped crates, as for a reload.
+    let events: Vec<_> = receiver.try_iter().collect();
+    match events[0] {
+        ChangeEvent::CrateRemoved(ref krate) => assert_eq!(krate.name, "types"),
+        ref e => panic!("unexpected event {:?}", e),
+    }
+    assert_eq!(cached.changes().unwrap()[0].kind, CrateChange::Removed);
+
+    fs::write(&cache_path, b"not a cache").unwrap();
+    assert_eq!(
+        cached.load_cache(&cache_path, Path::new("test_data/types")),
+        Err(AError::InvalidCache)
+    );
+    // The right magic and version, then a master crate map with a huge length.
+    fs::write(&cache_path, b"RLSA\x02\0\0\0\xff\xff\xff\xff\xff\xff\xff\xff").unwrap();
+    assert_eq!(
+        cached.load_cache(&cache_path, Path::new("test_data/types")),
+        Err(AError::InvalidCache)
+    );
+    fs::remove_file(&cache_path).unwrap();
+    assert_eq!(
+        cached.load_cache(&cache_path, Path::new("test_data/types")),
+        Err(AError::Io(io::ErrorKind::NotFound))
+    );
+}
+
 // TODO
 // check span functions
 // check complex programs
@@ -399,3 +653,977 @@ fn test_all_ref_unique() {
     let refs = host.find_all_refs(&spans[2], true, true);
     assert_eq!(refs.unwrap().len(), 3);
 }
+
+#[test]
+fn test_plan_rename() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/rename/save-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/rename"), Path::new("test_data/rename"))
+        .unwrap();
+
+    let qux = host.search("qux").unwrap();
+    let plan = host.plan_rename(&qux[2], "quux").unwrap();
+    assert_eq!(plan.id, host.search_for_id("qux").unwrap()[0]);
+    assert_eq!(plan.edits.len(), 1);
+    assert_eq!(plan.edits[0].0, Path::new("test_data/rename/src/main.rs"));
+    let mut expected = qux.clone();
+    expected.sort_by_key(|s| s.range.start());
+    assert_eq!(plan.edits[0].1, expected);
+
+    let refused = |span, name| match host.plan_rename(span, name) {
+        Err(AError::RenameRefused(RenameRefusal { reason, spans })) => (reason, spans),
+        r => panic!("expected a refusal, got {:?}", r),
+    };
+
+    let bar = host.search("bar").unwrap();
+    let (reason, spans) = refused(&bar[0], "bar2");
+    assert_eq!(reason, RenameRefusalReason::AliasedImport);
+    assert_eq!(spans, [bar[0].clone()]);
+
+    let (reason, spans) = refused(&qux[0], "bar");
+    assert_eq!(reason, RenameRefusalReason::NameCollision);
+    assert_eq!(spans, [bar[0].clone()]);
+    // `main` is not a sibling of `qux`.
+    assert!(host.plan_rename(&qux[0], "main").is_ok());
+
+    assert_eq!(refused(&qux[0], "1qux").0, RenameRefusalReason::InvalidName);
+    assert_eq!(refused(&qux[0], "a::b").0, RenameRefusalReason::InvalidName);
+    for keyword in &["fn", "self", "Self", "struct", "async"] {
+        assert_eq!(refused(&qux[0], keyword).0, RenameRefusalReason::InvalidName);
+    }
+    assert!(host.plan_rename(&qux[0], "fn_").is_ok());
+}
+
+#[test]
+fn test_imports() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/rename/save-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/rename"), Path::new("test_data/rename"))
+        .unwrap();
+
+    let main = Path::new("test_data/rename/src/main.rs");
+    let imports = host.imports_in_file(main).unwrap();
+    assert_eq!(imports.len(), 2);
+    let qux = host.search_for_id("qux").unwrap()[0];
+    let bar = host.search_for_id("bar").unwrap()[0];
+    let b = host.search_for_id("b").unwrap()[0];
+
+    assert_eq!(imports[0].kind, ImportKind::Use);
+    assert_eq!(imports[0].name, "qux");
+    assert_eq!(imports[0].target, Some(qux));
+    assert_eq!(imports[0].parent, Some(b));
+    assert!(imports[0].alias.is_none());
+
+    assert_eq!(imports[1].name, "baz");
+    assert_eq!(imports[1].target, Some(bar));
+    let alias = imports[1].alias.as_ref().unwrap();
+    assert_eq!(alias.range.row_start, Row::new_zero_indexed(7));
+    assert_eq!(alias.range.col_start, Column::new_zero_indexed(18));
+
+    assert_eq!(host.importers_of(bar).unwrap(), [imports[1].clone()]);
+    assert_eq!(host.importers_of(qux).unwrap(), [imports[0].clone()]);
+    assert!(host.importers_of(b).unwrap().is_empty());
+}
+
+#[test]
+fn test_ref_kinds() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/rename/save-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/rename"), Path::new("test_data/rename"))
+        .unwrap();
+
+    // The def of `qux`, its import and a call of it.
+    let qux = host.search_for_id("qux").unwrap()[0];
+    let refs = host.find_all_refs_by_id(qux).unwrap();
+    assert_eq!(refs.len(), 3);
+    assert!(host.ref_kinds(&refs[0]).is_err());
+    assert_eq!(host.ref_kinds(&refs[1]).unwrap(), [(qux, RefKind::Import)]);
+    assert_eq!(host.ref_kinds(&refs[2]).unwrap(), [(qux, RefKind::Function)]);
+
+    let calls = host.find_all_refs_of_kind(&refs[0], true, false, &[RefKind::Function]).unwrap();
+    assert_eq!(calls, [refs[0].clone(), refs[2].clone()]);
+    let imports = host.find_all_refs_of_kind(&refs[2], false, false, &[RefKind::Import]).unwrap();
+    assert_eq!(imports, [refs[1].clone()]);
+    let types = host.find_all_refs_of_kind(&refs[0], false, false, &[RefKind::Type]).unwrap();
+    assert!(types.is_empty());
+}
+
+#[test]
+fn test_glob_expansion() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/globs/save-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/globs"), Path::new("test_data/globs"))
+        .unwrap();
+
+    let file = Path::new("test_data/globs/src/main.rs");
+    let glob = |row, col| {
+        let row = Row::new_zero_indexed(row);
+        Span::new(row, row, Column::new_zero_indexed(col), Column::new_zero_indexed(col + 1), file)
+    };
+    // `use a::{bar, *};`
+    let glob_a = glob(8, 17);
+    assert_eq!(host.show_type(&glob_a).unwrap(), "baz, qux");
+
+    // `baz` is re-exported by `a`, from `c`.
+    let baz = host.search_for_id("baz").unwrap()[0];
+    let qux = host.search_for_id("qux").unwrap()[0];
+    assert_eq!(
+        host.glob_expansion(&glob_a).unwrap(),
+        [("baz".to_owned(), baz), ("qux".to_owned(), qux)]
+    );
+
+    // `use std::collections::*;`, std is not loaded.
+    let glob_std = glob(9, 26);
+    assert_eq!(host.glob_expansion(&glob_std), Err(AError::UnknownGlobModule(glob_std.clone())));
+
+    let foo = host.search("foo").unwrap();
+    assert_eq!(host.glob_expansion(&foo[0]), Err(AError::NoDefAtSpan(foo[0].clone())));
+}
+
+#[test]
+fn test_field_shorthand() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/shorthand/save-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/shorthand"), Path::new("test_data/shorthand"))
+        .unwrap();
+
+    let file = Path::new("test_data/shorthand/src/main.rs");
+    let span = |row, start, end| {
+        let row = Row::new_zero_indexed(row);
+        let (start, end) = (Column::new_zero_indexed(start), Column::new_zero_indexed(end));
+        Span::new(row, row, start, end, file)
+    };
+    let field_def = span(2, 4, 5);
+    let local_def = span(6, 8, 9);
+    // `x` in `Foo { x }` is a ref to both the field and the local.
+    let shorthand = span(7, 20, 21);
+    let field_use = span(8, 16, 17);
+    let field = host.id(&field_def).unwrap();
+    let local = host.id(&local_def).unwrap();
+
+    assert_eq!(host.ids(&shorthand).unwrap(), [field, local]);
+    assert_eq!(host.goto_defs(&shorthand).unwrap(), [field_def.clone(), local_def.clone()]);
+    assert_eq!(host.show_type(&shorthand).unwrap(), "u32");
+    assert_eq!(host.docs(&shorthand).unwrap(), " The x coordinate.\n");
+    assert_eq!(
+        host.ref_kinds(&shorthand).unwrap(),
+        [(field, RefKind::Variable), (local, RefKind::Variable)]
+    );
+
+    // Queries for a single def use the first, the field.
+    assert_eq!(host.id(&shorthand).unwrap(), field);
+    assert_eq!(host.crate_local_id(&shorthand).unwrap(), field);
+    assert_eq!(host.goto_def(&shorthand).unwrap(), field_def.clone());
+    assert_eq!(
+        host.id_at_position(file, Row::new_zero_indexed(7), Column::new_zero_indexed(20)).unwrap(),
+        field
+    );
+
+    // Refs are found for both defs.
+    assert_eq!(
+        host.find_all_refs(&shorthand, true, false).unwrap(),
+        [field_def, local_def, shorthand.clone(), field_use.clone()]
+    );
+    assert!(host.find_all_refs(&shorthand, true, true).unwrap().is_empty());
+    assert_eq!(
+        host.find_all_refs(&field_use, false, false).unwrap(),
+        [shorthand.clone(), field_use]
+    );
+
+    match host.plan_rename(&shorthand, "y") {
+        Err(AError::RenameRefused(refusal)) => {
+            assert_eq!(refusal.reason, RenameRefusalReason::AmbiguousRefs);
+            assert_eq!(refusal.spans, [shorthand]);
+        }
+        result => panic!("unexpected result {:?}", result),
+    }
+}
+
+#[test]
+fn test_type_hierarchy() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/rust-analysis").to_owned(),
+    ));
+    host.reload(
+        Path::new("test_data/rust-analysis"),
+        Path::new("test_data/rust-analysis"),
+    ).unwrap();
+
+    let id_for_qualname = |name: &str, qualname: &str| -> Id {
+        let ids: Vec<_> = host.search_for_id(name)
+            .unwrap()
+            .into_iter()
+            .filter(|id| host.get_def(*id).unwrap().qualname == qualname)
+            .collect();
+        assert_eq!(ids.len(), 1, "{}", qualname);
+        ids[0]
+    };
+    let reverse = id_for_qualname("Reverse", "core::cmp::Reverse");
+    let ordering = id_for_qualname("Ordering", "core::cmp::Ordering");
+    let ord = id_for_qualname("Ord", "core::cmp::Ord");
+    let partial_ord = id_for_qualname("PartialOrd", "core::cmp::PartialOrd");
+    let copy = id_for_qualname("Copy", "core::marker::Copy");
+    let debug = id_for_qualname("Debug", "core::fmt::Debug");
+    let raw_float = id_for_qualname("RawFloat", "core::num::dec2flt::rawfp::RawFloat");
+
+    let traits: HashSet<_> = host.traits_implemented_by(reverse).unwrap().into_iter().collect();
+    assert_eq!(traits, [ord, partial_ord].iter().cloned().collect());
+
+    let implementors = host.implementors_of_trait(ord).unwrap();
+    assert!(implementors.contains(&reverse));
+    assert!(implementors.contains(&ordering));
+    assert!(!implementors.contains(&ord));
+
+    let supertraits = host.supertraits(raw_float).unwrap();
+    assert!(supertraits.contains(&copy));
+    assert!(supertraits.contains(&debug));
+    assert!(host.subtraits(copy).unwrap().contains(&raw_float));
+    assert!(!host.implementors_of_trait(copy).unwrap().contains(&raw_float));
+
+    // Duplicate relations (e.g., several `From` impls) are only reported once.
+    let implemented = host.traits_implemented_by(ordering).unwrap();
+    let unique: HashSet<_> = implemented.iter().cloned().collect();
+    assert_eq!(implemented.len(), unique.len());
+}
+
+#[test]
+fn test_call_hierarchy() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/hello/save-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/hello"), Path::new("test_data/hello"))
+        .unwrap();
+
+    let main = host.search_for_id("main").unwrap()[0];
+    let print_hello = host.search_for_id("print_hello").unwrap()[0];
+
+    let incoming = host.incoming_calls(print_hello).unwrap();
+    assert_eq!(incoming.len(), 1);
+    assert_eq!(incoming[0].0, main);
+    assert_eq!(incoming[0].1.len(), 1);
+    let call = &incoming[0].1[0];
+    assert_eq!(call.range.row_start, Row::new_zero_indexed(6));
+    assert_eq!(call.range.col_start, Column::new_zero_indexed(4));
+
+    let outgoing = host.outgoing_calls(main).unwrap();
+    assert_eq!(outgoing, vec![(print_hello, vec![call.clone()])]);
+
+    assert!(host.incoming_calls(main).unwrap().is_empty());
+    // The callee of `println!` is in std, which is not loaded.
+    assert!(host.outgoing_calls(print_hello).unwrap().is_empty());
+}
+
+#[test]
+fn test_call_hierarchy_items() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/calls/save-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/calls"), Path::new("test_data/calls"))
+        .unwrap();
+
+    let one = host.search_for_id("one").unwrap()[0];
+    let two = host.search_for_id("two").unwrap()[0];
+    let three = host.search_for_id("three").unwrap()[0];
+    let five = host.search_for_id("five").unwrap()[0];
+    let main = host.search_for_id("main").unwrap()[0];
+    let rows = |calls: &[Span]| calls.iter().map(|s| s.range.row_start.0).collect::<Vec<_>>();
+
+    // The call in the initialiser of `FOUR` is not a call from `two`, and
+    // calls in and after the nested `three` have no known caller.
+    assert!(host.incoming_calls(three).unwrap().is_empty());
+    assert!(host.outgoing_calls(two).unwrap().is_empty());
+    assert!(host.outgoing_calls(three).unwrap().is_empty());
+
+    // A call in a closure is a call from the function containing it.
+    let incoming = host.incoming_calls(one).unwrap();
+    assert_eq!(incoming.len(), 1);
+    assert_eq!(incoming[0].0, main);
+    assert_eq!(rows(&incoming[0].1), [22]);
+
+    let mut incoming = host.incoming_calls(two).unwrap();
+    incoming.sort_by_key(|&(id, _)| id);
+    let mut expected = vec![(five, vec![17]), (main, vec![23])];
+    expected.sort();
+    assert_eq!(
+        incoming.iter().map(|&(id, ref calls)| (id, rows(calls))).collect::<Vec<_>>(),
+        expected
+    );
+
+    // Calls in a method body are from the method.
+    let outgoing = host.outgoing_calls(five).unwrap();
+    assert_eq!(outgoing.len(), 1);
+    assert_eq!(outgoing[0].0, two);
+    let mut outgoing: Vec<_> = host.outgoing_calls(main).unwrap().into_iter()
+        .map(|(id, _)| id)
+        .collect();
+    outgoing.sort();
+    let mut expected = vec![one, two, five];
+    expected.sort();
+    assert_eq!(outgoing, expected);
+}
+
+#[test]
+fn test_macro_refs() {
+    // Our test data doesn't include any macro defs, so add one for `println`
+    // by hand, within the span of its definition given by the macro ref.
+    let mut analysis = read_raw_analysis("test_data/hello/save-analysis/hello.json");
+    let mut def = analysis.defs.iter().find(|d| d.name == "main").unwrap().clone();
+    let callee_span = analysis.macro_refs[0].callee_span.clone();
+    def.kind = DefKind::Macro;
+    def.id.index = 1000;
+    def.name = "println".to_owned();
+    def.qualname = "println".to_owned();
+    def.span = data::SpanData {
+        line_end: callee_span.line_start,
+        column_start: Column::new_one_indexed(14),
+        column_end: Column::new_one_indexed(21),
+        ..callee_span
+    };
+    analysis.defs.push(def);
+
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/hello/no-save-analysis").to_owned(),
+    ));
+    host.reload_from_analysis(
+        vec![analysis],
+        Path::new("test_data/hello"),
+        Path::new("test_data/hello"),
+        &[],
+    ).unwrap();
+
+    let id = host.search_for_id("println").unwrap()[0];
+    let def_span = host.get_def(id).unwrap().span;
+
+    let (call, r) = host.ref_at_position(
+        Path::new("test_data/hello/src/main.rs"),
+        Row::new_zero_indexed(2),
+        Column::new_zero_indexed(6),
+    ).unwrap();
+    assert_eq!(r.some_id(), id);
+    assert_eq!(call.range.col_start, Column::new_zero_indexed(4));
+    assert_eq!(host.goto_def(&call).unwrap(), def_span);
+
+    let refs = host.find_all_refs(&def_span, true, false).unwrap();
+    assert_eq!(refs, vec![def_span, call]);
+}
+
+#[test]
+fn test_attributes() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/rust-analysis").to_owned(),
+    ));
+    host.reload(
+        Path::new("test_data/rust-analysis"),
+        Path::new("test_data/rust-analysis"),
+    ).unwrap();
+
+    let id_for_qualname = |name: &str, qualname: &str| -> Id {
+        host.search_for_id(name)
+            .unwrap()
+            .into_iter()
+            .find(|id| host.get_def(*id).unwrap().qualname == qualname)
+            .unwrap()
+    };
+    let sleep_ms = id_for_qualname("sleep_ms", "std::thread::sleep_ms");
+    let sleep = id_for_qualname("sleep", "std::thread::sleep");
+
+    let def = host.get_def(sleep_ms).unwrap();
+    let names: Vec<_> = def.attributes.iter().map(|a| a.name()).collect();
+    assert!(names.contains(&"stable"));
+    assert!(names.contains(&"rustc_deprecated"));
+    for attr in &def.attributes {
+        assert_eq!(attr.span.file, def.span.file);
+        assert!(attr.span.range.row_start < def.span.range.row_start);
+    }
+
+    assert_eq!(host.is_deprecated(sleep_ms), Ok(true));
+    assert_eq!(host.is_deprecated(sleep), Ok(false));
+    let unknown = Id::new(u64::max_value());
+    assert_eq!(host.is_deprecated(unknown), Err(AError::UnknownId(unknown)));
+
+    let deprecated = host.defs_with_attribute("rustc_deprecated").unwrap();
+    assert!(deprecated.contains(&sleep_ms));
+    assert!(!deprecated.contains(&sleep));
+    assert!(host.defs_with_attribute("test").unwrap().is_empty());
+}
+
+#[test]
+fn test_def_filter() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/types/save-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/types"), Path::new("test_data/types"))
+        .unwrap();
+
+    fn names(mut symbols: Vec<SymbolResult>) -> Vec<String> {
+        symbols.sort_by_key(|s| s.span.range.start());
+        symbols.into_iter().map(|s| s.name).collect()
+    }
+
+    let all = host.filter_defs(&DefFilter::new()).unwrap();
+    assert_eq!(all.len(), 20);
+
+    let filter = DefFilter::new().kinds(&[DefKind::Struct, DefKind::Union]);
+    assert_eq!(names(host.filter_defs(&filter).unwrap()), ["Foo", "TestUnion"]);
+
+    let foo = host.search_for_id("Foo").unwrap()[0];
+    let filter = DefFilter::new().parent(foo);
+    assert_eq!(names(host.filter_defs(&filter).unwrap()), ["f"]);
+    let filter = DefFilter::new().parent(foo).kinds(&[DefKind::Method]);
+    assert!(host.filter_defs(&filter).unwrap().is_empty());
+
+    let filter = DefFilter::new().kinds(&[DefKind::Function]).crates(&["types"]);
+    assert_eq!(names(host.filter_defs(&filter).unwrap()), ["main", "foo"]);
+    let filter = DefFilter::new().crates(&["std"]);
+    assert!(host.filter_defs(&filter).unwrap().is_empty());
+
+    let filter = DefFilter::new().distro_crate(false);
+    assert_eq!(host.filter_defs(&filter).unwrap().len(), all.len());
+    let filter = DefFilter::new().distro_crate(true);
+    assert!(host.filter_defs(&filter).unwrap().is_empty());
+
+    let filter = DefFilter::new().path_prefix(Path::new("test_data/types/src"));
+    assert_eq!(host.filter_defs(&filter).unwrap().len(), all.len());
+    let filter = DefFilter::new().path_prefix(Path::new("test_data/hello"));
+    assert!(host.filter_defs(&filter).unwrap().is_empty());
+}
+
+#[test]
+fn test_fuzzy_query() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/types/save-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/types"), Path::new("test_data/types"))
+        .unwrap();
+
+    let matches = host.query_symbols(SymbolQuery::fuzzy("foo")).unwrap();
+    let names: Vec<_> = matches.iter().map(|m| &m.def.name[..]).collect();
+    assert_eq!(names, ["foo", "Foo", "FooEnum"]);
+    assert!(matches.windows(2).all(|w| w[0].rank >= w[1].rank));
+
+    let defs = host.query_defs(SymbolQuery::fuzzy("tt").limit(1)).unwrap();
+    assert_eq!(defs.len(), 1);
+    assert_eq!(defs[0].name, "TestType");
+}
+
+#[test]
+fn test_qualified_query() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/rust-analysis").to_owned(),
+    ));
+    host.reload(
+        Path::new("test_data/rust-analysis"),
+        Path::new("test_data/rust-analysis"),
+    ).unwrap();
+
+    let qualnames = |query| -> Vec<String> {
+        let mut qualnames: Vec<_> = host.query_defs(query)
+            .unwrap()
+            .into_iter()
+            .map(|d| d.qualname)
+            .collect();
+        qualnames.sort();
+        qualnames
+    };
+
+    assert_eq!(
+        qualnames(SymbolQuery::prefix("std::collections::HashMap")),
+        ["std::collections::hash::map::HashMap"]
+    );
+    assert_eq!(
+        qualnames(SymbolQuery::prefix("std::io::Error")),
+        ["std::io::error::Error", "std::io::error::ErrorKind"]
+    );
+    assert!(qualnames(SymbolQuery::prefix("Error")).len() > 1);
+    assert_eq!(
+        qualnames(SymbolQuery::prefix("HashMap::new")),
+        ["std<HashMap<K, V, RandomState>>::new"]
+    );
+    assert!(qualnames(SymbolQuery::prefix("core::collections::HashMap")).is_empty());
+}
+
+#[test]
+fn test_pattern_query() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/types/save-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/types"), Path::new("test_data/types"))
+        .unwrap();
+
+    let names = |query| -> Vec<String> {
+        let mut names: Vec<_> = host.query_defs(query)
+            .unwrap()
+            .into_iter()
+            .map(|d| d.name)
+            .collect();
+        names.sort();
+        names
+    };
+
+    // Matching is case-insensitive.
+    let expected = ["TEST_CONST", "TEST_STATIC", "test_binding", "test_method", "test_module"];
+    assert_eq!(names(SymbolQuery::glob("test_*").unwrap()), expected);
+    assert_eq!(names(SymbolQuery::regex("test_[a-z]+").unwrap()), expected);
+    assert_eq!(names(SymbolQuery::regex("TEST_(C|S).*").unwrap()), ["TEST_CONST", "TEST_STATIC"]);
+    assert_eq!(names(SymbolQuery::levenshtein("fooo", 1).unwrap()), ["Foo", "foo"]);
+    assert_eq!(names(SymbolQuery::glob("test_module::*").unwrap()), ["TestType"]);
+}
+
+#[test]
+fn test_symbol_match() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/types/save-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/types"), Path::new("test_data/types"))
+        .unwrap();
+
+    let matches = host.query_symbols(SymbolQuery::subsequence("tstmth")).unwrap();
+    assert_eq!(matches.len(), 1);
+    let m = &matches[0];
+    assert_eq!(m.name, "test_method");
+    assert_eq!(m.krate.name, "types");
+    assert_eq!(m.ranges, [(0, 1), (2, 4), (5, 6), (7, 9)]);
+    assert!(m.rank > 0);
+    // The id can be used to follow up without another lookup.
+    assert_eq!(host.get_def(m.id).unwrap().name, "test_method");
+    assert!(!host.find_all_refs_by_id(m.id).unwrap().is_empty());
+
+    let m = &host.query_symbols(SymbolQuery::prefix("foo")).unwrap()[0];
+    assert_eq!(m.ranges, [(0, 3)]);
+    // Patterns don't say which parts of a name matched.
+    let m = &host.query_symbols(SymbolQuery::glob("*variant").unwrap()).unwrap()[0];
+    assert!(m.ranges.is_empty());
+    let m = &host.query_symbols(SymbolQuery::levenshtein("fooo", 1).unwrap()).unwrap()[0];
+    assert!(m.ranges.is_empty());
+
+    let file = m.span.file.clone();
+    for m in host.symbols(&file).unwrap() {
+        assert_eq!(m.krate.name, "types");
+        assert!(m.ranges.is_empty());
+    }
+}
+
+#[test]
+fn test_query_pages() {
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/types/save-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/types"), Path::new("test_data/types"))
+        .unwrap();
+
+    let pages = |query: fn() -> SymbolQuery, size: usize| -> Vec<Vec<Id>> {
+        let mut pages = vec![];
+        let mut cursor: Option<Cursor> = None;
+        loop {
+            let (page, next) = host.query_defs_page(query().limit(size), cursor).unwrap();
+            pages.push(page.into_iter().map(|m| m.id).collect());
+            match next {
+                Some(next) => cursor = Some(next),
+                None => return pages,
+            }
+        }
+    };
+
+    // `foo` and `Foo` have the same key, so the second page starts mid-key.
+    let all: Vec<_> = host.query_symbols(SymbolQuery::prefix("foo")).unwrap().into_iter()
+        .map(|m| m.id)
+        .collect();
+    assert_eq!(all.len(), 3);
+    let paged = pages(|| SymbolQuery::prefix("foo"), 1);
+    assert_eq!(paged.len(), 3);
+    let mut flat: Vec<_> = paged.into_iter().flat_map(|p| p).collect();
+    flat.sort();
+    let mut sorted = all.clone();
+    sorted.sort();
+    assert_eq!(flat, sorted);
+
+    for &size in &[1, 2, 4, 100] {
+        let all = host.query_symbols(SymbolQuery::subsequence("t")).unwrap();
+        let paged = pages(|| SymbolQuery::subsequence("t"), size);
+        let last = paged.len() - 1;
+        assert!(paged[..last].iter().all(|p| p.len() == size));
+        assert!(!paged[last].is_empty() && paged[last].len() <= size);
+        let ids: HashSet<_> = paged.iter().flat_map(|p| p.iter().cloned()).collect();
+        assert_eq!(ids.len(), all.len());
+        assert!(all.iter().all(|m| ids.contains(&m.id)));
+    }
+
+    // A limit of zero is no limit, rather than an empty page.
+    let all = host.query_symbols(SymbolQuery::subsequence("t")).unwrap();
+    assert_eq!(pages(|| SymbolQuery::subsequence("t"), 0), vec![
+        all.iter().map(|m| m.id).collect::<Vec<_>>(),
+    ]);
+
+    // Fuzzy pages follow the ranking.
+    let ranked: Vec<_> = host.query_symbols(SymbolQuery::fuzzy("t")).unwrap().into_iter()
+        .map(|m| m.rank)
+        .collect();
+    let (page, cursor) = host.query_defs_page(SymbolQuery::fuzzy("t").limit(3), None).unwrap();
+    assert_eq!(page.iter().map(|m| m.rank).collect::<Vec<_>>(), &ranked[..3]);
+    let (page, _) = host.query_defs_page(SymbolQuery::fuzzy("t").limit(3), cursor).unwrap();
+    assert_eq!(page.iter().map(|m| m.rank).collect::<Vec<_>>(), &ranked[3..6]);
+}
+
+#[test]
+fn test_outline() {
+    fn names(nodes: &[OutlineNode]) -> Vec<String> {
+        nodes
+            .iter()
+            .map(|n| {
+                let name = match n.item {
+                    OutlineItem::Def(_, ref def) => def.name.clone(),
+                    OutlineItem::Impl(_) => "impl".to_owned(),
+                };
+                if n.children.is_empty() {
+                    name
+                } else {
+                    format!("{}({})", name, names(&n.children).join(" "))
+                }
+            })
+            .collect()
+    }
+
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/types/save-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/types"), Path::new("test_data/types"))
+        .unwrap();
+    let outline = host.outline(Path::new("test_data/types/src/main.rs")).unwrap();
+    assert_eq!(
+        names(&outline),
+        [
+            "Foo(f)",
+            "main",
+            "foo",
+            "TEST_CONST",
+            "TEST_STATIC",
+            "test_module(TestType)",
+            "TestUnion(f1)",
+            "TestTrait(test_method)",
+            "FooEnum(TupleVariant StructVariant(x))",
+        ]
+    );
+
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/exprs/save-analysis").to_owned(),
+    ));
+    host.reload(Path::new("test_data/exprs"), Path::new("test_data/exprs"))
+        .unwrap();
+    let outline = host.outline(Path::new("test_data/exprs/src/main.rs")).unwrap();
+    assert_eq!(names(&outline), ["Foo", "impl(bar)", "foo", "main"]);
+    match outline[1].item {
+        OutlineItem::Impl(ref imp) => {
+            assert_eq!(imp.self_id, Some(host.search_for_id("Foo").unwrap()[0]));
+            assert_eq!(imp.trait_id, None);
+        }
+        _ => panic!("expected an impl"),
+    }
+
+    assert!(host.outline(Path::new("test_data/exprs/src/lib.rs")).is_err());
+}
+
+#[test]
+fn test_changes() {
+    let load = |analysis: data::Analysis| {
+        let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+            Path::new("test_data/hello/no-save-analysis").to_owned(),
+        ));
+        host.track_changes(true);
+        host.reload_from_analysis(
+            vec![analysis],
+            Path::new("test_data/hello"),
+            Path::new("test_data/hello"),
+            &[],
+        ).unwrap();
+        host
+    };
+    let main_rs = Path::new("test_data/hello/src/main.rs");
+    let other_rs = Path::new("test_data/hello/src/other.rs");
+
+    let host = load(read_raw_analysis("test_data/hello/save-analysis/hello.json"));
+    let changes = host.changes().unwrap();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].krate.name, "hello");
+    assert_eq!(changes[0].files, [main_rs]);
+    assert_eq!(changes[0].added.len(), 4);
+    assert!(changes[0].removed.is_empty() && changes[0].modified.is_empty());
+    let print_hello = host.search_for_id("print_hello").unwrap()[0];
+    let name = host.search_for_id("name").unwrap()[0];
+    let main_refs = host.find_all_refs_by_id(host.search_for_id("main").unwrap()[0]).unwrap();
+
+    // Change the docs of one def, remove another and add one in a new file.
+    let mut analysis = read_raw_analysis("test_data/hello/save-analysis/hello.json");
+    analysis.defs.retain(|d| d.name != "name");
+    let mut goodbye = analysis.defs.iter().find(|d| d.name == "print_hello").unwrap().clone();
+    goodbye.id.index = 5;
+    goodbye.name = "goodbye".to_owned();
+    goodbye.qualname = "::goodbye".to_owned();
+    goodbye.span.file_name = PathBuf::from("src/other.rs");
+    analysis.defs.push(goodbye);
+    for def in analysis.defs.iter_mut().filter(|d| d.name == "print_hello") {
+        def.docs = "Prints hello.".to_owned();
+    }
+    let updated = load(analysis);
+    let goodbye = updated.search_for_id("goodbye").unwrap()[0];
+
+    let changes = {
+        let mut fresh = updated.analysis.lock().unwrap();
+        let (id, per_crate) = fresh.as_mut().unwrap().per_crate.drain().next().unwrap();
+        let mut analysis = host.analysis.lock().unwrap();
+        let analysis = analysis.as_mut().unwrap();
+        let old = analysis.update(id.clone(), per_crate);
+        ChangeSet::new(id.clone(), old.as_ref(), analysis.per_crate.get(&id))
+    };
+    assert_eq!(changes.files, [main_rs, other_rs]);
+    assert_eq!(changes.added, [goodbye]);
+    assert_eq!(changes.removed, [name]);
+    assert_eq!(changes.modified, [print_hello]);
+
+    assert_eq!(host.get_def(print_hello).unwrap().docs, "Prints hello.");
+    assert!(host.get_def(name).is_err());
+    assert_eq!(host.symbols(other_rs).unwrap()[0].id, goodbye);
+    let main_id = host.search_for_id("main").unwrap()[0];
+    assert_eq!(host.find_all_refs_by_id(main_id).unwrap(), main_refs);
+
+    // Updating with the same data changes nothing.
+    let changes = {
+        let same = load(read_raw_analysis("test_data/hello/save-analysis/hello.json"));
+        let mut fresh = same.analysis.lock().unwrap();
+        let (id, per_crate) = fresh.as_mut().unwrap().per_crate.drain().next().unwrap();
+        let original = load(read_raw_analysis("test_data/hello/save-analysis/hello.json"));
+        let mut analysis = original.analysis.lock().unwrap();
+        let analysis = analysis.as_mut().unwrap();
+        let old = analysis.update(id.clone(), per_crate);
+        ChangeSet::new(id.clone(), old.as_ref(), analysis.per_crate.get(&id))
+    };
+    assert!(changes.is_empty());
+
+    // A hard reload with no data removes the crate.
+    host.hard_reload(Path::new("test_data/hello"), Path::new("test_data/hello")).unwrap();
+    let changes = host.changes().unwrap();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].files, [main_rs, other_rs]);
+    assert_eq!(changes[0].removed.len(), 4);
+    assert!(changes[0].added.is_empty());
+
+    // Once changes are no longer tracked, reloads record none.
+    host.track_changes(false);
+    host.hard_reload(Path::new("test_data/hello"), Path::new("test_data/hello")).unwrap();
+    assert!(host.changes().unwrap().is_empty());
+}
+
+#[test]
+fn test_reload_unchanged_files() {
+    let load = |host: &AnalysisHost<TestAnalysisLoader>, analysis: &data::Analysis| {
+        host.reload_from_analysis(
+            vec![analysis.clone()],
+            Path::new("test_data/hello"),
+            Path::new("test_data/hello"),
+            &[],
+        ).unwrap();
+    };
+    let new_host = || {
+        AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+            Path::new("test_data/hello/no-save-analysis").to_owned(),
+        ))
+    };
+    // The per-file indexes, with refs unordered.
+    let indexes = |host: &AnalysisHost<TestAnalysisLoader>| {
+        let analysis = host.analysis.lock().unwrap();
+        let c = analysis.as_ref().unwrap().per_crate.values().next().unwrap();
+        let refs: HashSet<_> = c.ref_spans
+            .iter()
+            .flat_map(|(id, refs)| {
+                refs.iter().map(move |&(ref span, kind)| (*id, span.clone(), kind))
+            })
+            .collect();
+        (c.def_id_for_span.clone(), c.spans_per_file.clone(), c.defs_per_file.clone(), refs)
+    };
+    let main_rs = Path::new("test_data/hello/src/main.rs");
+
+    let analysis = read_raw_analysis("test_data/hello/save-analysis/hello.json");
+    let host = new_host();
+    load(&host, &analysis);
+    host.loader.lock().unwrap().soft_reload = true;
+
+    // Add a def in a new file. The data for main.rs is reused, and is the same
+    // as if it were lowered again.
+    let mut analysis = analysis;
+    let mut goodbye = analysis.defs.iter().find(|d| d.name == "print_hello").unwrap().clone();
+    goodbye.id.index = 5;
+    goodbye.name = "goodbye".to_owned();
+    goodbye.qualname = "::goodbye".to_owned();
+    goodbye.span.file_name = PathBuf::from("src/other.rs");
+    analysis.defs.push(goodbye);
+    load(&host, &analysis);
+    let fresh = new_host();
+    load(&fresh, &analysis);
+    assert_eq!(indexes(&host), indexes(&fresh));
+
+    // Mark the entries for main.rs, to tell whether they are lowered again.
+    let marker = Id::new(!0 - 1);
+    let mark = || {
+        let mut a = host.analysis.lock().unwrap();
+        let c = a.as_mut().unwrap().per_crate.values_mut().next().unwrap();
+        c.defs_per_file.get_mut(main_rs).unwrap().push(marker);
+    };
+    let marked = || indexes(&host).2[main_rs].contains(&marker);
+
+    // Defs are always lowered, so a change to docs is seen even though the
+    // per-file data is reused.
+    mark();
+    for def in analysis.defs.iter_mut().filter(|d| d.name == "print_hello") {
+        def.docs = "Prints hello.".to_owned();
+    }
+    load(&host, &analysis);
+    assert!(marked());
+    let print_hello = host.search_for_id("print_hello").unwrap()[0];
+    assert_eq!(host.get_def(print_hello).unwrap().docs, "Prints hello.");
+
+    // Renaming a def changes main.rs, so it is lowered again.
+    for def in analysis.defs.iter_mut().filter(|d| d.name == "print_hello") {
+        def.qualname = "::print_hi".to_owned();
+    }
+    load(&host, &analysis);
+    assert!(!marked());
+    let fresh = new_host();
+    load(&fresh, &analysis);
+    assert_eq!(indexes(&host), indexes(&fresh));
+}
+
+#[test]
+fn test_subscribe() {
+    use std::sync::{mpsc, Arc, Mutex};
+
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/hello/save-analysis").to_owned(),
+    ));
+    let (sender, receiver) = mpsc::channel();
+    let sender = Mutex::new(sender);
+    let id = host
+        .subscribe(move |e: &ChangeEvent| sender.lock().unwrap().send(e.clone()).unwrap())
+        .unwrap();
+
+    host.reload(Path::new("test_data/hello"), Path::new("test_data/hello")).unwrap();
+    let events: Vec<_> = receiver.try_iter().collect();
+    assert_eq!(events.len(), 2);
+    let krate = match events[0] {
+        ChangeEvent::CrateAdded(ref krate) => krate.clone(),
+        ref e => panic!("unexpected event {:?}", e),
+    };
+    assert_eq!(krate.name, "hello");
+    match events[1] {
+        ChangeEvent::DefsChanged { krate: ref k, ref added, ref removed, ref modified } => {
+            assert_eq!(k, &krate);
+            assert_eq!(added.len(), 4);
+            assert!(removed.is_empty() && modified.is_empty());
+        }
+        ref e => panic!("unexpected event {:?}", e),
+    }
+
+    // Reloading the same data changes nothing.
+    host.reload(Path::new("test_data/hello"), Path::new("test_data/hello")).unwrap();
+    assert_eq!(receiver.try_iter().count(), 0);
+
+    host.unsubscribe(id).unwrap();
+    let empty = AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/hello/no-save-analysis").to_owned(),
+    ));
+    *host.loader.lock().unwrap() = empty.loader.into_inner().unwrap();
+    host.hard_reload(Path::new("test_data/hello"), Path::new("test_data/hello")).unwrap();
+    assert_eq!(receiver.try_iter().count(), 0);
+    // With no subscribers, and changes not tracked, changes are not found.
+    assert!(host.changes().unwrap().is_empty());
+
+    // Callbacks may subscribe (and unsubscribe) without deadlocking.
+    let host = Arc::new(AnalysisHost::new_with_loader(TestAnalysisLoader::new(
+        Path::new("test_data/hello/save-analysis").to_owned(),
+    )));
+    let weak = Arc::downgrade(&host);
+    host.subscribe(move |_: &ChangeEvent| {
+        weak.upgrade().unwrap().subscribe(|_: &ChangeEvent| {}).unwrap();
+    }).unwrap();
+    host.reload(Path::new("test_data/hello"), Path::new("test_data/hello")).unwrap();
+    assert_eq!(host.subscribers.lock().unwrap().len(), 3);
+}
+
+#[test]
+fn test_remove_missing_crates() {
+    let dir = env::temp_dir().join("rls-analysis-test_remove_missing_crates");
+    fs::create_dir_all(&dir).unwrap();
+    fs::copy("test_data/hello/save-analysis/hello.json", dir.join("hello.json")).unwrap();
+    let types_json = dir.join("types.json");
+    fs::copy("test_data/types/save-analysis/types-e743a203eefaed5b.json", &types_json).unwrap();
+
+    let host = AnalysisHost::new_with_loader(TestAnalysisLoader::new(dir.clone()));
+    host.reload(Path::new("test_data"), Path::new("test_data")).unwrap();
+    let test_trait = host.search_for_id("TestTrait").unwrap();
+    assert_eq!(test_trait.len(), 1);
+    let types = host.analysis.lock().unwrap().as_ref().unwrap().crate_names["types"][0].clone();
+
+    host.loader.lock().unwrap().soft_reload = true;
+    host.track_changes(true);
+    fs::remove_file(&types_json).unwrap();
+    host.reload(Path::new("test_data"), Path::new("test_data")).unwrap();
+
+    assert!(host.search_for_id("TestTrait").unwrap().is_empty());
+    assert_eq!(host.search_for_id("print_hello").unwrap().len(), 1);
+    let changes = host.changes().unwrap();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].krate, types);
+    assert_eq!(changes[0].kind, CrateChange::Removed);
+    assert_eq!(changes[0].removed.len(), 20);
+    assert!(!host.analysis.lock().unwrap().as_ref().unwrap().crate_names.contains_key("types"));
+    // The crate keeps its number, so refs to it from other crates stay valid.
+    let num = host.master_crate_map.lock().unwrap()[&types];
+
+    // The crate can be read again, and gets the same number (and ids).
+    fs::copy("test_data/types/save-analysis/types-e743a203eefaed5b.json", &types_json).unwrap();
+    host.reload(Path::new("test_data"), Path::new("test_data")).unwrap();
+    assert_eq!(host.search_for_id("TestTrait").unwrap(), test_trait);
+    assert_eq!(host.search_for_id("print_hello").unwrap().len(), 1);
+    assert_eq!(host.master_crate_map.lock().unwrap()[&types], num);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_watcher() {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let dir = env::temp_dir().join("rls-analysis-test_watcher");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::copy("test_data/hello/save-analysis/hello.json", dir.join("hello.json")).unwrap();
+
+    let host = Arc::new(AnalysisHost::new_with_loader(TestAnalysisLoader::new(dir.clone())));
+    host.reload(Path::new("test_data"), Path::new("test_data")).unwrap();
+    assert!(host.search_for_id("TestTrait").unwrap().is_empty());
+
+    let watcher = Watcher::new(
+        host.clone(),
+        Path::new("test_data"),
+        Path::new("test_data"),
+        Duration::from_millis(10),
+        Duration::from_millis(50),
+    );
+    fs::copy("test_data/types/save-analysis/types-e743a203eefaed5b.json", dir.join("types.json"))
+        .unwrap();
+
+    let start = Instant::now();
+    while host.search_for_id("TestTrait").unwrap().is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(10), "watcher did not reload");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(host.search_for_id("print_hello").unwrap().len(), 1);
+
+    drop(watcher);
+    fs::remove_dir_all(&dir).unwrap();
+}
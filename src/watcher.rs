@@ -0,0 +1,119 @@
+// Copyright 2018 The RLS Project Developers.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use listings::{DirectoryListing, ListingKind};
+use loader::AnalysisLoader;
+use AnalysisHost;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Watches the analysis data directories of a host (those returned by its
+/// loader's `search_directories`), and reloads the host in the background when
+/// the data changes.
+///
+/// Directories are polled every `poll_interval`. Cargo writes the data for
+/// many crates in a burst, so after a change the watcher waits until nothing
+/// has changed for `debounce` before reloading. Reloads are done by `reload`,
+/// which lowers the new data before swapping it in at once, so the host can be
+/// queried throughout and never answers from a partly reloaded analysis.
+///
+/// The watcher stops when it is dropped.
+pub struct Watcher {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watcher {
+    pub fn new<L>(
+        host: Arc<AnalysisHost<L>>,
+        path_prefix: &Path,
+        base_dir: &Path,
+        poll_interval: Duration,
+        debounce: Duration,
+    ) -> Watcher
+    where
+        L: AnalysisLoader + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel();
+        let path_prefix = path_prefix.to_owned();
+        let base_dir = base_dir.to_owned();
+        // Take the first snapshot now, so that we catch any changes made as
+        // soon as we return.
+        let mut last = snapshot(&host);
+        let thread = thread::spawn(move || {
+            // Waits for `timeout`, returning false if the watcher has stopped.
+            let wait = |timeout| match stopped.recv_timeout(timeout) {
+                Err(RecvTimeoutError::Timeout) => true,
+                _ => false,
+            };
+
+            while wait(poll_interval) {
+                let mut pending = snapshot(&host);
+                if pending == last {
+                    continue;
+                }
+
+                let mut quiet_since = Instant::now();
+                while quiet_since.elapsed() < debounce {
+                    if !wait(::std::cmp::min(poll_interval, debounce)) {
+                        return;
+                    }
+                    let current = snapshot(&host);
+                    if current != pending {
+                        pending = current;
+                        quiet_since = Instant::now();
+                    }
+                }
+
+                info!("analysis data changed, reloading");
+                if let Err(e) = host.reload(&path_prefix, &base_dir) {
+                    warn!("error reloading analysis data: {:?}", e);
+                }
+                last = pending;
+            }
+        });
+
+        Watcher { stop: Some(stop), thread: Some(thread) }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        // Dropping the sender wakes the thread, which then exits.
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// The modification times of all files in the host's search directories.
+// Every directory is listed again on each poll, so a poll costs a `stat` of
+// each data file; keep `poll_interval` long for targets with many crates.
+fn snapshot<L: AnalysisLoader>(host: &AnalysisHost<L>) -> HashMap<PathBuf, SystemTime> {
+    let dirs = match host.loader.lock() {
+        Ok(loader) => loader.search_directories(),
+        Err(_) => return HashMap::new(),
+    };
+    let mut result = HashMap::new();
+    for dir in dirs {
+        if let Ok(listing) = DirectoryListing::from_path(&dir.path) {
+            for l in listing.files {
+                if let ListingKind::File(time) = l.kind {
+                    result.insert(dir.path.join(&l.name), time);
+                }
+            }
+        }
+    }
+    result
+}
@@ -0,0 +1,25 @@
+const fn one() -> u32 {
+    1
+}
+
+fn two() -> u32 {
+    fn three() -> u32 {
+        one()
+    }
+    three() + one()
+}
+
+const FOUR: u32 = one() + 3;
+
+struct Five;
+
+impl Five {
+    fn five(&self) -> u32 {
+        two() + 3
+    }
+}
+
+fn main() {
+    let six = || one() + Five.five();
+    let _ = two() + FOUR + six();
+}
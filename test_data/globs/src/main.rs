@@ -0,0 +1,24 @@
+mod a {
+    pub use c::baz;
+
+    pub fn bar() {}
+    pub fn qux() {}
+}
+
+mod b {
+    use a::{bar, *};
+    use std::collections::*;
+
+    pub fn foo() {
+        qux();
+        bar();
+        baz();
+        let _: HashMap<u32, u32> = HashMap::new();
+    }
+}
+
+mod c {
+    pub fn baz() {}
+}
+
+fn main() {}
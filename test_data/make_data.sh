@@ -36,3 +36,15 @@ build exprs exprs/save-analysis
 
 # all_ref_unique
 build rename rename/save-analysis
+
+# Glob imports
+build globs globs/save-analysis
+
+# Field shorthand
+build shorthand shorthand/save-analysis
+
+# Calls
+build calls calls/save-analysis
+
+# Several crates
+build multi_crate multi_crate/save-analysis
@@ -0,0 +1,41 @@
+extern crate units;
+
+use units::{Area, Length};
+
+pub trait Shape {
+    fn area(&self) -> Area;
+
+    fn is_empty(&self) -> bool {
+        self.area() == Area(0.0)
+    }
+}
+
+pub struct Rect {
+    pub width: Length,
+    pub height: Length,
+}
+
+impl Shape for Rect {
+    fn area(&self) -> Area {
+        self.width.times(self.height)
+    }
+}
+
+pub struct Square(pub Length);
+
+impl Shape for Square {
+    fn area(&self) -> Area {
+        Rect { width: self.0, height: self.0 }.area()
+    }
+}
+
+pub fn largest<'a>(shapes: &'a [Box<dyn Shape>]) -> Option<&'a dyn Shape> {
+    let mut result: Option<&'a dyn Shape> = None;
+    for shape in shapes {
+        match result {
+            Some(r) if r.area() >= shape.area() => {}
+            _ => result = Some(&**shape),
+        }
+    }
+    result
+}
@@ -0,0 +1,15 @@
+extern crate shapes;
+extern crate units;
+
+use shapes::{Rect, Shape, Square};
+use units::Length;
+
+fn main() {
+    let shapes: Vec<Box<dyn Shape>> = vec![
+        Box::new(Square(Length::from_cm(2.0))),
+        Box::new(Rect { width: Length(15.0), height: units::ZERO }),
+    ];
+    if let Some(shape) = shapes::largest(&shapes) {
+        println!("{:?}", shape.area());
+    }
+}
@@ -0,0 +1,19 @@
+/// A length in millimetres.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub struct Length(pub f64);
+
+impl Length {
+    pub fn from_cm(cm: f64) -> Length {
+        Length(cm * 10.0)
+    }
+
+    pub fn times(self, other: Length) -> Area {
+        Area(self.0 * other.0)
+    }
+}
+
+/// An area in square millimetres.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub struct Area(pub f64);
+
+pub const ZERO: Length = Length(0.0);
@@ -0,0 +1,10 @@
+struct Foo {
+    /// The x coordinate.
+    x: u32,
+}
+
+fn main() {
+    let x = 42;
+    let foo = Foo { x };
+    let _ = foo.x;
+}